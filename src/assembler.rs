@@ -0,0 +1,617 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::cpu::{PROG_MEM_START_OFFSET, XO_RAM_SIZE};
+
+// Assembler for Octo syntax (https://github.com/JohnEarnest/Octo), supports labels, ':const', ':alias',
+// ':call', ':byte', 'loop'/'while'/'again', 'if ... then' / 'if ... begin ... else ... end' and raw
+// numbers as data. Comparisons other than == / != and macros aren't supported. Execution starts at
+// 0x200, or at ': main' if it isn't the first thing in the file.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    column: usize
+}
+
+// label references are patched once every label is known
+enum FixupKind {
+    Addr, // low 12 bits of the opcode
+    Long  // the 16 bit word after 'i := long'
+}
+
+struct Fixup<'a> {
+    offset: usize,
+    kind: FixupKind,
+    token: Token<'a>
+}
+
+// open control flow blocks, the offsets are jumps waiting for their target
+enum Block<'a> {
+    Loop { start: u16, breaks: Vec<usize>, token: Token<'a> },
+    If { jump: usize, token: Token<'a> },
+    Else { jump: usize, token: Token<'a> }
+}
+
+struct Assembler<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    rom: Vec<u8>,
+    labels: HashMap<&'a str, u16>,
+    consts: HashMap<&'a str, i32>,
+    aliases: HashMap<&'a str, u8>,
+    fixups: Vec<Fixup<'a>>,
+    blocks: Vec<Block<'a>>
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError>
+{
+    let mut asm = Assembler {
+        tokens: tokenize(source),
+        pos: 0,
+        rom: Vec::new(),
+        labels: HashMap::new(),
+        consts: HashMap::new(),
+        aliases: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new()
+    };
+
+    // like Octo, start at ': main' when the program doesn't begin with it
+    let main_label = asm.tokens.windows(2).position(|pair| pair[0].text == ":" && pair[1].text == "main");
+    if let Some(index) = main_label.filter(|&index| index > 0) {
+        asm.emit(0x1000);
+        asm.fixups.push(Fixup { offset: 0, kind: FixupKind::Addr, token: asm.tokens[index + 1] });
+    }
+
+    while asm.pos < asm.tokens.len() {
+        asm.statement()?;
+    }
+
+    if let Some(block) = asm.blocks.last() {
+        let (token, expected) = match block {
+            Block::Loop { token, .. } => (token, "again"),
+            Block::If { token, .. } | Block::Else { token, .. } => (token, "end")
+        };
+        return Err(error_at(token, format!("'{}' is missing its '{}'", token.text, expected)));
+    }
+
+    let end = PROG_MEM_START_OFFSET as usize + asm.rom.len();
+    if let Some(last) = asm.tokens.last().filter(|_| end > XO_RAM_SIZE) {
+        return Err(error_at(last, format!("the program runs past the end of memory, it ends at {:#X}", end)));
+    }
+
+    asm.resolve_fixups()?;
+    return Ok(asm.rom);
+}
+
+fn tokenize(source: &str) -> Vec<Token<'_>>
+{
+    let mut tokens = Vec::new();
+    for (line_index, line) in source.lines().enumerate() {
+        let code = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line
+        };
+
+        let mut start: Option<usize> = None;
+        for (index, c) in code.char_indices().chain(std::iter::once((code.len(), ' '))) {
+            match (c.is_whitespace(), start) {
+                (true, Some(begin)) => {
+                    tokens.push(Token { text: &code[begin..index], line: line_index + 1, column: begin + 1 });
+                    start = None;
+                },
+                (false, None) => start = Some(index),
+                _ => {}
+            }
+        }
+    }
+
+    return tokens;
+}
+
+fn error_at(token: &Token, message: String) -> AsmError
+{
+    AsmError { line: token.line, column: token.column, message }
+}
+
+fn parse_number(text: &str) -> Option<i32>
+{
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text)
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i32::from_str_radix(bin, 2).ok()?
+    } else {
+        digits.parse::<i32>().ok()?
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register_name(text: &str) -> Option<u8>
+{
+    let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    if digit.len() != 1 {
+        return None;
+    }
+
+    u8::from_str_radix(digit, 16).ok()
+}
+
+// the skip that executes the next instruction only when the condition holds, and its inverse
+fn invert_skip(opcode: u16) -> u16
+{
+    match opcode & 0xF0FF {
+        0xE09E => (opcode & 0xFF00) | 0xA1,
+        0xE0A1 => (opcode & 0xFF00) | 0x9E,
+        _ => match opcode >> 12 {
+            0x3 => (opcode & 0x0FFF) | 0x4000,
+            0x4 => (opcode & 0x0FFF) | 0x3000,
+            0x5 => (opcode & 0x0FFF) | 0x9000,
+            _ => (opcode & 0x0FFF) | 0x5000
+        }
+    }
+}
+
+impl<'a> Assembler<'a> {
+    fn next(&mut self) -> Result<Token<'a>, AsmError>
+    {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(*token)
+            },
+            None => {
+                let last = self.tokens.last().copied().unwrap_or(Token { text: "", line: 1, column: 1 });
+                Err(AsmError { line: last.line, column: last.column + last.text.len(), message: "unexpected end of file".to_string() })
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str>
+    {
+        self.tokens.get(self.pos).map(|token| token.text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), AsmError>
+    {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(error_at(&token, format!("expected '{}', found '{}'", text, token.text)));
+        }
+
+        return Ok(());
+    }
+
+    // address of the next byte, 'token' is blamed if the program has run past the end of memory
+    fn here(&self, token: &Token) -> Result<u16, AsmError>
+    {
+        let addr = PROG_MEM_START_OFFSET as usize + self.rom.len();
+        if addr >= XO_RAM_SIZE {
+            return Err(error_at(token, format!("the program runs past the end of memory at {:#X}", addr)));
+        }
+
+        return Ok(addr as u16);
+    }
+
+    fn emit(&mut self, opcode: u16)
+    {
+        self.rom.push((opcode >> 8) as u8);
+        self.rom.push((opcode & 0xFF) as u8);
+    }
+
+    // patches the target of a jump emitted for 'else', 'end', 'while' or 'again'
+    fn patch_jump(&mut self, offset: usize, addr: u16, token: &Token) -> Result<(), AsmError>
+    {
+        if addr > 0xFFF {
+            return Err(error_at(token, format!("'{}' jumps to {:#X}, which doesn't fit in 12 bits", token.text, addr)));
+        }
+
+        self.patch_addr(offset, addr);
+        return Ok(());
+    }
+
+    fn patch_addr(&mut self, offset: usize, addr: u16)
+    {
+        self.rom[offset] = (self.rom[offset] & 0xF0) | (addr >> 8) as u8;
+        self.rom[offset + 1] = (addr & 0xFF) as u8;
+    }
+
+    fn register(&mut self) -> Result<u8, AsmError>
+    {
+        let token = self.next()?;
+        self.register_from(&token)
+    }
+
+    fn register_from(&self, token: &Token) -> Result<u8, AsmError>
+    {
+        if let Some(reg) = parse_register_name(token.text).or_else(|| self.aliases.get(token.text).copied()) {
+            return Ok(reg);
+        }
+
+        Err(error_at(token, format!("expected a register, found '{}'", token.text)))
+    }
+
+    fn is_register(&self, text: &str) -> bool
+    {
+        parse_register_name(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    fn value(&self, token: &Token) -> Option<i32>
+    {
+        parse_number(token.text).or_else(|| self.consts.get(token.text).copied())
+    }
+
+    fn number(&mut self, min: i32, max: i32) -> Result<i32, AsmError>
+    {
+        let token = self.next()?;
+        match self.value(&token) {
+            Some(value) if (min..=max).contains(&value) => Ok(value),
+            Some(value) => Err(error_at(&token, format!("{} is out of range, expected {} - {}", value, min, max))),
+            None => Err(error_at(&token, format!("expected a number, found '{}'", token.text)))
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, AsmError>
+    {
+        Ok(self.number(-128, 255)? as u8)
+    }
+
+    // emits 'opcode' with a 12 bit address taken from a number, constant or (possibly later) label
+    fn emit_addr(&mut self, opcode: u16) -> Result<(), AsmError>
+    {
+        let token = self.next()?;
+        let offset = self.rom.len();
+        self.emit(opcode);
+        match self.value(&token) {
+            Some(addr) if (0..=0xFFF).contains(&addr) => self.patch_addr(offset, addr as u16),
+            Some(addr) => return Err(error_at(&token, format!("address {:#X} doesn't fit in 12 bits", addr))),
+            None => self.fixups.push(Fixup { offset, kind: FixupKind::Addr, token })
+        }
+
+        return Ok(());
+    }
+
+    fn new_name(&self, token: &Token) -> Result<(), AsmError>
+    {
+        let taken = self.labels.contains_key(token.text) || self.consts.contains_key(token.text) || self.aliases.contains_key(token.text);
+        if taken || parse_number(token.text).is_some() || parse_register_name(token.text).is_some() {
+            return Err(error_at(token, format!("'{}' is already defined or reserved", token.text)));
+        }
+
+        return Ok(());
+    }
+
+    // 'vX == vY', 'vX != NN', 'vX key', 'vX -key' as the skip used by 'if ... then'
+    fn condition(&mut self) -> Result<u16, AsmError>
+    {
+        let x = self.register()? as u16;
+        let op = self.next()?;
+        match op.text {
+            "key" => return Ok(0xE0A1 | (x << 8)),
+            "-key" => return Ok(0xE09E | (x << 8)),
+            "==" | "!=" => {},
+            _ => return Err(error_at(&op, format!("unsupported comparison '{}', expected ==, !=, key or -key", op.text)))
+        }
+
+        let rhs = self.tokens.get(self.pos).copied();
+        let opcode = match rhs {
+            Some(token) if self.is_register(token.text) => {
+                self.pos += 1;
+                let y = self.register_from(&token)? as u16;
+                let skip_ne = 0x9000 | (x << 8) | (y << 4);
+                if op.text == "==" { skip_ne } else { invert_skip(skip_ne) }
+            },
+            _ => {
+                let n = self.byte()? as u16;
+                let skip_ne = 0x4000 | (x << 8) | n;
+                if op.text == "==" { skip_ne } else { invert_skip(skip_ne) }
+            }
+        };
+
+        return Ok(opcode);
+    }
+
+    fn statement(&mut self) -> Result<(), AsmError>
+    {
+        let token = self.next()?;
+        match token.text {
+            ":" => {
+                let name = self.next()?;
+                self.new_name(&name)?;
+                let addr = self.here(&name)?;
+                self.labels.insert(name.text, addr);
+            },
+            ":const" => {
+                let name = self.next()?;
+                self.new_name(&name)?;
+                let value = self.number(-0x8000, 0xFFFF)?;
+                self.consts.insert(name.text, value);
+            },
+            ":alias" => {
+                let name = self.next()?;
+                self.new_name(&name)?;
+                let reg = self.register()?;
+                self.aliases.insert(name.text, reg);
+            },
+            ":call" => self.emit_addr(0x2000)?,
+            ":byte" => {
+                let n = self.byte()?;
+                self.rom.push(n);
+            },
+            "native" => self.emit_addr(0x0000)?,
+            "jump" => self.emit_addr(0x1000)?,
+            "jump0" => self.emit_addr(0xB000)?,
+            "clear" => self.emit(0x00E0),
+            "return" => self.emit(0x00EE),
+            "scroll-down" => {
+                let n = self.number(0, 15)? as u16;
+                self.emit(0x00C0 | n);
+            },
+            "scroll-up" => {
+                let n = self.number(0, 15)? as u16;
+                self.emit(0x00D0 | n);
+            },
+            "scroll-right" => self.emit(0x00FB),
+            "scroll-left" => self.emit(0x00FC),
+            "exit" => self.emit(0x00FD),
+            "lores" => self.emit(0x00FE),
+            "hires" => self.emit(0x00FF),
+            "plane" => {
+                let n = self.number(0, 3)? as u16;
+                self.emit(0xF001 | (n << 8));
+            },
+            "audio" => self.emit(0xF002),
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let n = self.number(0, 15)? as u16;
+                self.emit(0xD000 | (x << 8) | (y << 4) | n);
+            },
+            "bcd" => {
+                let x = self.register()? as u16;
+                self.emit(0xF033 | (x << 8));
+            },
+            "save" | "load" => {
+                let x = self.register()? as u16;
+                if self.peek() == Some("-") {
+                    self.pos += 1;
+                    let y = self.register()? as u16;
+                    let low = if token.text == "save" { 0x2 } else { 0x3 };
+                    self.emit(0x5000 | (x << 8) | (y << 4) | low);
+                } else {
+                    let low = if token.text == "save" { 0x55 } else { 0x65 };
+                    self.emit(0xF000 | (x << 8) | low);
+                }
+            },
+            "saveflags" => {
+                let x = self.register()? as u16;
+                self.emit(0xF075 | (x << 8));
+            },
+            "loadflags" => {
+                let x = self.register()? as u16;
+                self.emit(0xF085 | (x << 8));
+            },
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()? as u16;
+                let low = match token.text { "delay" => 0x15, "buzzer" => 0x18, _ => 0x3A };
+                self.emit(0xF000 | (x << 8) | low);
+            },
+            "i" => self.index_statement()?,
+            "if" => {
+                let skip = self.condition()?;
+                let body = self.next()?;
+                match body.text {
+                    "then" => self.emit(skip),
+                    "begin" => {
+                        // skip over the jump to 'else' / 'end' when the condition holds
+                        self.emit(invert_skip(skip));
+                        let jump = self.rom.len();
+                        self.emit(0x1000);
+                        self.blocks.push(Block::If { jump, token });
+                    },
+                    _ => return Err(error_at(&body, format!("expected 'then' or 'begin', found '{}'", body.text)))
+                }
+            },
+            "else" => {
+                let Some(Block::If { jump, token: if_token }) = self.blocks.pop() else {
+                    return Err(error_at(&token, "'else' without a matching 'if ... begin'".to_string()));
+                };
+
+                let else_jump = self.rom.len();
+                self.emit(0x1000);
+                let here = self.here(&token)?;
+                self.patch_jump(jump, here, &token)?;
+                self.blocks.push(Block::Else { jump: else_jump, token: if_token });
+            },
+            "end" => {
+                let jump = match self.blocks.pop() {
+                    Some(Block::If { jump, .. }) | Some(Block::Else { jump, .. }) => jump,
+                    _ => return Err(error_at(&token, "'end' without a matching 'if ... begin'".to_string()))
+                };
+
+                let here = self.here(&token)?;
+                self.patch_jump(jump, here, &token)?;
+            },
+            "loop" => {
+                let start = self.here(&token)?;
+                self.blocks.push(Block::Loop { start, breaks: Vec::new(), token });
+            },
+            "while" => {
+                let skip = self.condition()?;
+                self.emit(invert_skip(skip));
+                let jump = self.rom.len();
+                self.emit(0x1000);
+                match self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop { .. })) {
+                    Some(Block::Loop { breaks, .. }) => breaks.push(jump),
+                    _ => return Err(error_at(&token, "'while' outside of a 'loop'".to_string()))
+                }
+            },
+            "again" => {
+                let Some(Block::Loop { start, breaks, .. }) = self.blocks.pop() else {
+                    return Err(error_at(&token, "'again' without a matching 'loop'".to_string()));
+                };
+
+                let jump = self.rom.len();
+                self.emit(0x1000);
+                self.patch_jump(jump, start, &token)?;
+                let here = self.here(&token)?;
+                for jump in breaks {
+                    self.patch_jump(jump, here, &token)?;
+                }
+            },
+            _ if self.is_register(token.text) => self.register_statement(&token)?,
+            _ if self.value(&token).is_some() => {
+                match self.value(&token) {
+                    Some(n) if (-128..=255).contains(&n) => self.rom.push(n as u8),
+                    _ => return Err(error_at(&token, format!("data byte '{}' is out of range", token.text)))
+                }
+            },
+            _ if token.text.starts_with(':') => {
+                return Err(error_at(&token, format!("unsupported directive '{}'", token.text)));
+            },
+            _ => {
+                // a bare label name is a call
+                let offset = self.rom.len();
+                self.emit(0x2000);
+                self.fixups.push(Fixup { offset, kind: FixupKind::Addr, token });
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn index_statement(&mut self) -> Result<(), AsmError>
+    {
+        let op = self.next()?;
+        match op.text {
+            "+=" => {
+                let x = self.register()? as u16;
+                self.emit(0xF01E | (x << 8));
+            },
+            ":=" => match self.peek() {
+                Some("hex") | Some("bighex") => {
+                    let big = self.next()?.text == "bighex";
+                    let x = self.register()? as u16;
+                    self.emit(if big { 0xF030 } else { 0xF029 } | (x << 8));
+                },
+                Some("long") => {
+                    self.pos += 1;
+                    let token = self.next()?;
+                    self.emit(0xF000);
+                    let offset = self.rom.len();
+                    self.emit(0x0000);
+                    match self.value(&token) {
+                        Some(addr) if (0..=0xFFFF).contains(&addr) => {
+                            self.rom[offset] = (addr >> 8) as u8;
+                            self.rom[offset + 1] = (addr & 0xFF) as u8;
+                        },
+                        Some(addr) => return Err(error_at(&token, format!("address {:#X} doesn't fit in 16 bits", addr))),
+                        None => self.fixups.push(Fixup { offset, kind: FixupKind::Long, token })
+                    }
+                },
+                _ => self.emit_addr(0xA000)?
+            },
+            _ => return Err(error_at(&op, format!("expected ':=' or '+=' after 'i', found '{}'", op.text)))
+        }
+
+        return Ok(());
+    }
+
+    fn register_statement(&mut self, reg: &Token) -> Result<(), AsmError>
+    {
+        let x = self.register_from(reg)? as u16;
+        let op = self.next()?;
+        let rhs = self.tokens.get(self.pos).copied();
+        let rhs_is_register = rhs.map(|token| self.is_register(token.text)).unwrap_or(false);
+
+        if rhs_is_register {
+            let y = self.register()? as u16;
+            let n = match op.text {
+                ":=" => 0x0, "|=" => 0x1, "&=" => 0x2, "^=" => 0x3, "+=" => 0x4,
+                "-=" => 0x5, ">>=" => 0x6, "=-" => 0x7, "<<=" => 0xE,
+                _ => return Err(error_at(&op, format!("unknown operator '{}'", op.text)))
+            };
+            self.emit(0x8000 | (x << 8) | (y << 4) | n);
+            return Ok(());
+        }
+
+        match (op.text, rhs.map(|token| token.text)) {
+            (":=", Some("random")) => {
+                self.pos += 1;
+                let n = self.byte()? as u16;
+                self.emit(0xC000 | (x << 8) | n);
+            },
+            (":=", Some("delay")) => {
+                self.pos += 1;
+                self.emit(0xF007 | (x << 8));
+            },
+            (":=", Some("key")) => {
+                self.pos += 1;
+                self.emit(0xF00A | (x << 8));
+            },
+            (":=", _) => {
+                let n = self.byte()? as u16;
+                self.emit(0x6000 | (x << 8) | n);
+            },
+            ("+=", _) => {
+                let n = self.byte()? as u16;
+                self.emit(0x7000 | (x << 8) | n);
+            },
+            ("-=", _) => {
+                let n = self.byte()?;
+                self.emit(0x7000 | (x << 8) | n.wrapping_neg() as u16);
+            },
+            ("|=", _) | ("&=", _) | ("^=", _) | ("=-", _) | (">>=", _) | ("<<=", _) => {
+                return Err(error_at(&op, format!("operator '{}' needs a register on the right", op.text)));
+            },
+            _ => return Err(error_at(&op, format!("unknown operator '{}'", op.text)))
+        }
+
+        return Ok(());
+    }
+
+    fn resolve_fixups(&mut self) -> Result<(), AsmError>
+    {
+        let fixups = std::mem::take(&mut self.fixups);
+        for fixup in fixups {
+            let Some(&addr) = self.labels.get(fixup.token.text) else {
+                return Err(error_at(&fixup.token, format!("undefined label '{}'", fixup.token.text)));
+            };
+
+            match fixup.kind {
+                FixupKind::Addr => {
+                    if addr > 0xFFF {
+                        return Err(error_at(&fixup.token, format!("label '{}' at {:#X} doesn't fit in 12 bits, use 'i := long'", fixup.token.text, addr)));
+                    }
+                    self.patch_addr(fixup.offset, addr);
+                },
+                FixupKind::Long => {
+                    self.rom[fixup.offset] = (addr >> 8) as u8;
+                    self.rom[fixup.offset + 1] = (addr & 0xFF) as u8;
+                }
+            }
+        }
+
+        return Ok(());
+    }
+}
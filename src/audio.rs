@@ -0,0 +1,106 @@
+use crate::cpu::*;
+
+pub const SAMPLE_RATE: u32 = 44100; // Hz
+pub const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / TIMER_CLOCK_SPEED) as usize;
+const XO_PATTERN_BITS: f32 = 128.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioSettings {
+    pub volume: f32, // 0.0 - 1.0
+    pub pitch: f32,  // Hz, buzzer tone (XO-CHIP roms with an audio pattern use their own pitch register)
+    pub muted: bool
+}
+
+pub const DEFAULT_AUDIO_SETTINGS: AudioSettings = AudioSettings {
+    volume: 0.25,
+    pitch: 440.0,
+    muted: false
+};
+
+// Turns the sound timer into samples: a square wave while it's non-zero, or the
+// XO-CHIP audio pattern played back at the pitch register's rate. Produces one
+// frame's worth of mono f32 samples at a time so any output (audio device, wav) can consume it.
+pub struct Audio {
+    settings: AudioSettings,
+    phase: f32 // 0.0 - 1.0 through the current square wave period / audio pattern
+}
+
+impl Audio {
+
+    // appends SAMPLES_PER_FRAME samples for the frame the cpu just ran
+    pub fn generate_frame(&mut self, cpu: &CPU, out: &mut Vec<f32>)
+    {
+        if !cpu.is_sound_playing() || self.settings.muted {
+            // restart the wave on the next beep so every beep sounds the same
+            self.phase = 0.0;
+            out.resize(out.len() + SAMPLES_PER_FRAME, 0.0);
+            return;
+        }
+
+        let pattern = cpu.audio_pattern();
+        let use_pattern = cpu.mode() == CPUMode::XoChip && pattern.iter().any(|byte| *byte != 0);
+        let step = if use_pattern {
+            // 4000 Hz at pitch 64, one octave every 48 steps
+            let bit_rate = 4000.0 * 2f32.powf((cpu.pitch() as f32 - 64.0) / 48.0);
+            bit_rate / XO_PATTERN_BITS / SAMPLE_RATE as f32
+        } else {
+            self.settings.pitch / SAMPLE_RATE as f32
+        };
+
+        for _ in 0..SAMPLES_PER_FRAME {
+            let high = if use_pattern {
+                let bit = (self.phase * XO_PATTERN_BITS) as usize;
+                (pattern[bit / 8] >> (7 - (bit % 8))) & 1 > 0
+            } else {
+                self.phase < 0.5
+            };
+
+            out.push(if high { self.settings.volume } else { -self.settings.volume });
+            self.phase = (self.phase + step).fract();
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: AudioSettings)
+    {
+        self.settings = settings;
+    }
+
+    #[inline(always)]
+    pub fn settings(&self) -> AudioSettings {
+        self.settings
+    }
+}
+
+pub fn make_audio(settings: AudioSettings) -> Audio
+{
+    Audio { settings, phase: 0.0 }
+}
+
+// 16-bit PCM mono RIFF/WAVE file
+pub fn encode_wav(samples: &[f32], sample_rate: u32) -> Vec<u8>
+{
+    let data_len = (samples.len() * 2) as u32;
+    let mut wav: Vec<u8> = Vec::with_capacity(44 + data_len as usize);
+
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes()); // fmt chunk size
+    wav.extend_from_slice(&1u16.to_le_bytes());  // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes());  // mono
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // byte rate
+    wav.extend_from_slice(&2u16.to_le_bytes());  // block align
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        wav.extend_from_slice(&pcm.to_le_bytes());
+    }
+
+    return wav;
+}
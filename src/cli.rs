@@ -0,0 +1,117 @@
+// Command line parsing for the subcommands: positional arguments plus '--flag value', '--flag=value'
+// and '--switch' flags in any order, '--' ends the flags. Each command lists its flags so it can
+// reject unknown ones and print its own --help.
+
+pub struct Flag {
+    pub name: &'static str,          // without the leading dashes
+    pub value: Option<&'static str>, // placeholder shown in the help, None for switches
+    pub help: &'static str
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub args: &'static str, // positional arguments for the usage line, e.g. "<rom>"
+    pub about: &'static str,
+    pub flags: &'static [Flag]
+}
+
+pub struct ParsedArgs {
+    pub positionals: Vec<String>,
+    pub help: bool,
+    flags: Vec<(&'static str, Option<String>)> // in the order given, flags may repeat
+}
+
+impl ParsedArgs {
+
+    #[inline(always)]
+    pub fn has(&self, name: &str) -> bool {
+        self.flags.iter().any(|(flag, _)| *flag == name)
+    }
+
+    // the last value given for the flag
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.flags.iter().rev().find(|(flag, _)| *flag == name).and_then(|(_, value)| value.as_deref())
+    }
+
+    // every value given for a flag that can be repeated
+    pub fn values(&self, name: &str) -> Vec<&str> {
+        self.flags.iter().filter(|(flag, _)| *flag == name).filter_map(|(_, value)| value.as_deref()).collect()
+    }
+}
+
+pub fn parse_args(command: &Command, args: &[String]) -> Result<ParsedArgs, String>
+{
+    let mut parsed = ParsedArgs { positionals: Vec::new(), help: false, flags: Vec::new() };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            parsed.positionals.extend(args.cloned());
+            break;
+        }
+
+        if arg == "--help" || arg == "-h" {
+            parsed.help = true;
+            continue;
+        }
+
+        let Some(flag_text) = arg.strip_prefix("--") else {
+            // single dash flags like '-mode' are most likely the old style, point at the new name
+            if let Some(flag) = arg.strip_prefix('-').and_then(|name| find_flag(command, name.split('=').next().unwrap_or(""))) {
+                return Err(format!("unknown flag '{}', did you mean '--{}'?", arg, flag.name));
+            }
+            if arg.len() > 1 && arg.starts_with('-') {
+                return Err(format!("unknown flag '{}' for 'chip8 {}'", arg, command.name));
+            }
+
+            parsed.positionals.push(arg.clone());
+            continue;
+        };
+
+        let (name, inline_value) = match flag_text.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (flag_text, None)
+        };
+
+        let Some(flag) = find_flag(command, name) else {
+            return Err(format!("unknown flag '--{}' for 'chip8 {}'", name, command.name));
+        };
+
+        let value = match (flag.value, inline_value) {
+            (None, None) => None,
+            (None, Some(_)) => return Err(format!("--{} doesn't take a value", flag.name)),
+            (Some(_), Some(value)) => Some(value),
+            (Some(placeholder), None) => match args.next() {
+                Some(value) => Some(value.clone()),
+                None => return Err(format!("--{} needs a value {}", flag.name, placeholder))
+            }
+        };
+
+        parsed.flags.push((flag.name, value));
+    }
+
+    return Ok(parsed);
+}
+
+fn find_flag<'a>(command: &'a Command, name: &str) -> Option<&'a Flag>
+{
+    command.flags.iter().find(|flag| flag.name == name)
+}
+
+pub fn command_help(command: &Command) -> String
+{
+    let mut out = format!("Usage: chip8 {} {} [flags]\n{}\n\nFlags:\n", command.name, command.args, command.about);
+
+    let flag_column = |flag: &Flag| match flag.value {
+        Some(placeholder) => format!("--{} {}", flag.name, placeholder),
+        None => format!("--{}", flag.name)
+    };
+    let width = command.flags.iter().map(|flag| flag_column(flag).len()).max().unwrap_or(0).max("-h, --help".len());
+
+    // help text that runs over several lines continues under the first
+    let indent = format!("\n{}", " ".repeat(width + 4));
+    for flag in command.flags.iter() {
+        out += &format!("  {:width$}  {}\n", flag_column(flag), flag.help.replace('\n', &indent), width = width);
+    }
+    out += &format!("  {:width$}  show this help\n", "-h, --help", width = width);
+    return out;
+}
@@ -0,0 +1,340 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::cpu::*;
+use crate::keymap::*;
+use crate::keypad::NUM_KEYS;
+use crate::machine::DEFAULT_CLOCK_SPEED;
+use crate::quirks::*;
+
+pub const DEFAULT_SCALE: u32 = 10; // window pixels per lores pixel
+pub const MAX_SCALE: u32 = 40;
+pub const DEFAULT_SAVE_DIR: &str = "saves";
+// 0xRRGGBB for the background, plane 0, plane 1 and both planes (only XO-CHIP draws to plane 1)
+pub const DEFAULT_PALETTE: [u32; 4] = [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555];
+pub const NAMED_PALETTES: [(&str, [u32; 4]); 5] = [
+    ("mono", DEFAULT_PALETTE),
+    ("green", [0x0C1A0C, 0x33FF66, 0x1F8A3C, 0xA8FFBF]), // green phosphor monitor
+    ("amber", [0x1A1000, 0xFFB000, 0x9C6A00, 0xFFDD88]), // amber phosphor monitor
+    ("lcd", [0x9BBC0F, 0x0F380F, 0x306230, 0x8BAC0F]),   // green-grey handheld LCD
+    ("octo", [0x996600, 0xFFCC00, 0xFF6600, 0x662200])   // Octo's default colours
+];
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+// how the display fills a window that isn't a multiple of its size, leftover space is letterboxed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scaling {
+    Integer, // whole window pixels per chip8 pixel, every pixel the same size
+    Aspect   // as large as fits at the display's aspect ratio
+}
+
+// Persistent emulator settings from <config dir>/chip8/config.toml, command line flags override them.
+// The core doesn't read it, frontends pass the values on (clock speed, mode) or use them themselves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub speed: u32,        // instructions per second
+    pub scale: u32,        // window pixels per lores pixel, the window starts at this size
+    pub scaling: Scaling,
+    pub fullscreen: bool,  // start in fullscreen
+    pub mode: CPUMode,     // for roms started without --mode
+    pub save_dir: PathBuf,
+    pub palette: [u32; 4], // see DEFAULT_PALETTE
+    pub quirks: BTreeMap<String, bool>, // flags changed from the mode's preset, see Config::apply_quirks
+    pub keys: KeyBindings,
+    // per rom (file name without the extension), the bindings above with the rom's overrides applied
+    pub rom_keys: BTreeMap<String, KeyBindings>
+}
+
+impl Config {
+
+    pub fn key_bindings(&self, rom_name: &str) -> &KeyBindings {
+        self.rom_keys.get(rom_name).unwrap_or(&self.keys)
+    }
+
+    // on top of the preset the mode (or the rom database) picked, --quirks goes on top of this
+    pub fn apply_quirks(&self, quirks: &mut Quirks)
+    {
+        for (name, enabled) in self.quirks.iter() {
+            quirks.set_flag(name, *enabled).expect("quirk names are checked when the config is parsed");
+        }
+    }
+}
+
+// the file as written, anything left out keeps its default
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    speed: Option<u32>,
+    scale: Option<u32>,
+    scaling: Option<String>,
+    fullscreen: Option<bool>,
+    mode: Option<String>,
+    save_dir: Option<PathBuf>,
+    palette: Option<PaletteSetting>,
+    quirks: Option<BTreeMap<String, bool>>,
+    keys: Option<BTreeMap<String, KeyList>>,
+    hotkeys: Option<BTreeMap<String, KeyList>>,
+    gamepad: Option<BTreeMap<String, String>>,
+    roms: Option<BTreeMap<String, RomConfigFile>>
+}
+
+// [roms."<rom name>"] sections
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RomConfigFile {
+    keys: Option<BTreeMap<String, KeyList>>,
+    hotkeys: Option<BTreeMap<String, KeyList>>,
+    gamepad: Option<BTreeMap<String, String>>
+}
+
+// a palette name ("amber") or its colours (["#000000", "#FFFFFF"])
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "a palette name or a list of \"#RRGGBB\" colours")]
+enum PaletteSetting {
+    Name(String),
+    Colors(Vec<String>)
+}
+
+// a key can be bound to one keyboard key ("W") or several (["W", "Up"])
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "a keyboard key name or a list of them")]
+enum KeyList {
+    One(String),
+    Many(Vec<String>)
+}
+
+impl KeyList {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            KeyList::One(key) => vec![key],
+            KeyList::Many(keys) => keys
+        }
+    }
+}
+
+pub fn make_config() -> Config
+{
+    Config {
+        speed: DEFAULT_CLOCK_SPEED,
+        scale: DEFAULT_SCALE,
+        scaling: Scaling::Integer,
+        fullscreen: false,
+        mode: CPUMode::Chip8,
+        save_dir: PathBuf::from(DEFAULT_SAVE_DIR),
+        palette: DEFAULT_PALETTE,
+        quirks: BTreeMap::new(),
+        keys: make_key_bindings(),
+        rom_keys: BTreeMap::new()
+    }
+}
+
+// $XDG_CONFIG_HOME/chip8, falling back to ~/.config (%APPDATA% on Windows)
+pub fn config_dir() -> Option<PathBuf>
+{
+    let absolute_var = |name: &str| std::env::var_os(name).map(PathBuf::from).filter(|path| path.is_absolute());
+    let config_dir = absolute_var("XDG_CONFIG_HOME")
+        .or_else(|| absolute_var("HOME").map(|home| home.join(".config")))
+        .or_else(|| absolute_var("APPDATA"))?;
+
+    return Some(config_dir.join("chip8"));
+}
+
+pub fn default_config_path() -> Option<PathBuf>
+{
+    config_dir().map(|dir| dir.join(CONFIG_FILE_NAME))
+}
+
+// reads the config, or writes out the defaults if there isn't one yet so there's a file to edit
+pub fn load_config(path: &Path) -> Result<Config, String>
+{
+    if !path.exists() {
+        let written = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|()| fs::write(path, default_config_text()));
+        match written {
+            Ok(()) => println!("Wrote the default settings to {}", path.display()),
+            Err(err) => println!("Failed to write the default settings to {}: {}", path.display(), err)
+        }
+        return Ok(make_config());
+    }
+
+    let text = fs::read_to_string(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    return parse_config(&text).map_err(|err| format!("{}: {}", path.display(), err));
+}
+
+pub fn parse_config(text: &str) -> Result<Config, String>
+{
+    let file: ConfigFile = toml::from_str(text).map_err(|err| err.to_string().trim_end().to_string())?;
+    let mut config = make_config();
+
+    if let Some(speed) = file.speed {
+        if speed < TIMER_CLOCK_SPEED {
+            return Err(format!("speed {} is too slow, it's at least {} instructions per second", speed, TIMER_CLOCK_SPEED));
+        }
+        config.speed = speed;
+    }
+
+    if let Some(scale) = file.scale {
+        if !(1..=MAX_SCALE).contains(&scale) {
+            return Err(format!("scale {} is out of range, expected 1 - {}", scale, MAX_SCALE));
+        }
+        config.scale = scale;
+    }
+
+    if let Some(scaling) = file.scaling {
+        config.scaling = parse_scaling(&scaling).ok_or(format!("unknown scaling '{}', expected integer or aspect", scaling))?;
+    }
+
+    if let Some(fullscreen) = file.fullscreen {
+        config.fullscreen = fullscreen;
+    }
+
+    if let Some(mode) = file.mode {
+        config.mode = parse_mode(&mode).ok_or(format!("unknown mode '{}', expected chip8, chip48, schip or xochip", mode))?;
+    }
+
+    if let Some(save_dir) = file.save_dir {
+        config.save_dir = save_dir;
+    }
+
+    if let Some(palette) = file.palette {
+        let items: Vec<&str> = match &palette {
+            PaletteSetting::Name(name) => vec![name.as_str()],
+            PaletteSetting::Colors(colors) => colors.iter().map(String::as_str).collect()
+        };
+        config.palette = parse_palette(&items, config.palette)?;
+    }
+
+    for (name, enabled) in file.quirks.unwrap_or_default() {
+        default_quirks(&config.mode).set_flag(&name, enabled)
+            .map_err(|err| format!("{} in [quirks], expected one of {}", err, QUIRK_NAMES.join(", ")))?;
+        config.quirks.insert(name, enabled);
+    }
+
+    apply_key_bindings(&mut config.keys, file.keys, file.hotkeys, file.gamepad)?;
+    for (rom_name, rom) in file.roms.unwrap_or_default() {
+        let mut keys = config.keys.clone();
+        apply_key_bindings(&mut keys, rom.keys, rom.hotkeys, rom.gamepad).map_err(|err| format!("[roms.\"{}\"]: {}", rom_name, err))?;
+        config.rom_keys.insert(rom_name, keys);
+    }
+
+    return Ok(config);
+}
+
+// [keys] are by chip8 key 0 - F, [hotkeys] by hotkey name and [gamepad] by controller button
+fn apply_key_bindings(bindings: &mut KeyBindings, keys: Option<BTreeMap<String, KeyList>>, hotkeys: Option<BTreeMap<String, KeyList>>,
+                      gamepad: Option<BTreeMap<String, String>>) -> Result<(), String>
+{
+    for (chip8_key, keyboard_keys) in keys.unwrap_or_default() {
+        let key = u8::from_str_radix(&chip8_key, 16).ok().filter(|key| (*key as usize) < NUM_KEYS)
+            .ok_or(format!("invalid key '{}' in [keys], expected a chip8 key 0 - F", chip8_key))?;
+        bindings.bind(KeyAction::Keypad(key), keyboard_keys.into_vec())?;
+    }
+
+    for (name, keyboard_keys) in hotkeys.unwrap_or_default() {
+        let names: Vec<String> = HOTKEYS.iter().map(Hotkey::name).collect();
+        let hotkey = Hotkey::from_name(&name)
+            .ok_or(format!("unknown hotkey '{}' in [hotkeys], expected one of {}", name, names.join(", ")))?;
+        bindings.bind(KeyAction::Hotkey(hotkey), keyboard_keys.into_vec())?;
+    }
+
+    // a chip8 key or hotkey name per button, "" unbinds it
+    for (button, action_name) in gamepad.unwrap_or_default() {
+        let action = match action_name.as_str() {
+            "" => None,
+            name => Some(KeyAction::from_name(name)
+                .ok_or(format!("invalid action '{}' for controller button {}, expected a chip8 key 0 - F or a hotkey name", name, button))?)
+        };
+        bindings.bind_button(&button, action)?;
+    }
+
+    return Ok(());
+}
+
+// one of NAMED_PALETTES, or up to four "#RRGGBB" colours. Colours left out at the end keep the ones in
+// 'base', so two are enough for the lores modes
+pub fn parse_palette(items: &[&str], base: [u32; 4]) -> Result<[u32; 4], String>
+{
+    if let [name] = items {
+        if let Some((_, palette)) = NAMED_PALETTES.iter().find(|(palette_name, _)| palette_name == name) {
+            return Ok(*palette);
+        }
+    }
+
+    if items.len() > base.len() {
+        return Err(format!("palette has {} colours, at most {} are used", items.len(), base.len()));
+    }
+
+    let mut palette = base;
+    for (slot, text) in palette.iter_mut().zip(items.iter()) {
+        *slot = parse_color(text).ok_or_else(|| {
+            let names: Vec<&str> = NAMED_PALETTES.iter().map(|(name, _)| *name).collect();
+            format!("invalid palette colour '{}', expected \"#RRGGBB\" or a palette name ({})", text, names.join(", "))
+        })?;
+    }
+
+    return Ok(palette);
+}
+
+pub fn parse_scaling(text: &str) -> Option<Scaling>
+{
+    match text {
+        "integer" => Some(Scaling::Integer),
+        "aspect" => Some(Scaling::Aspect),
+        _ => None
+    }
+}
+
+// "#RRGGBB" (or without the #) => 0xRRGGBB
+pub fn parse_color(text: &str) -> Option<u32>
+{
+    let hex = text.trim().strip_prefix('#').unwrap_or(text.trim());
+    if hex.len() != 6 {
+        return None;
+    }
+
+    return u32::from_str_radix(hex, 16).ok();
+}
+
+// the file written on first run, every setting with its default so it's easy to change
+pub fn default_config_text() -> String
+{
+    let mut text = String::new();
+    text += "# chip8 emulator settings, command line flags override these\n\n";
+    text += &format!("speed = {}  # instructions per second\n", DEFAULT_CLOCK_SPEED);
+    text += &format!("scale = {}   # window pixels per lores pixel, the window can be resized after\n", DEFAULT_SCALE);
+    text += "scaling = \"integer\"  # integer (same size pixels) or aspect (fill the window), the rest is letterboxed\n";
+    text += "fullscreen = false\n";
+    text += "mode = \"chip8\"  # for roms started without --mode: chip8, chip48, schip or xochip\n";
+    text += &format!("save_dir = \"{}\"  # save slots, one folder per rom\n\n", DEFAULT_SAVE_DIR);
+
+    let colors: Vec<String> = DEFAULT_PALETTE.iter().map(|color| format!("\"#{:06X}\"", color)).collect();
+    let names: Vec<&str> = NAMED_PALETTES.iter().map(|(name, _)| *name).collect();
+    text += "# background, plane 0, plane 1 and both planes (the last two are only used by XO-CHIP),\n";
+    text += &format!("# or one of the named palettes: {}\n", names.join(", "));
+    text += &format!("palette = [{}]\n\n", colors.join(", "));
+
+    text += "# quirks to change from the mode's preset for every rom, --quirks overrides these. Flags:\n";
+    text += &format!("# {}\n[quirks]\n# shift_vy = false\n\n", QUIRK_NAMES.join(", "));
+
+    text += "# keyboard key (SDL scancode name) for each chip8 key, a list binds several: 5 = [\"W\", \"Up\"]\n[keys]\n";
+    for (chip8_key, key_name) in DEFAULT_KEYS.iter().enumerate() {
+        text += &format!("{:X} = \"{}\"\n", chip8_key, key_name);
+    }
+
+    text += "\n# slots save, Shift + the slot key loads (load1 - load9 load directly). [] unbinds a hotkey\n[hotkeys]\n";
+    for hotkey in HOTKEYS.iter() {
+        if let Some(key) = hotkey.default_key() {
+            text += &format!("{} = \"{}\"\n", hotkey.name(), key);
+        }
+    }
+
+    text += "\n# controller button (SDL name: a, b, x, y, start, back, dpup, leftshoulder, ...) => chip8 key or hotkey, \"\" unbinds it\n[gamepad]\n";
+    for (button, action) in make_key_bindings().gamepad.iter() {
+        text += &format!("{} = \"{}\"\n", button, action.name());
+    }
+
+    text += "\n# per rom keys, hotkeys and gamepad tables, by the rom's file name without the extension\n";
+    text += "# [roms.\"Tetris [Fran Dachille, 1991]\".keys]\n# 4 = [\"Q\", \"Up\"]\n";
+
+    return text;
+}
@@ -2,17 +2,24 @@
 #![allow(dead_code, unused_variables)]
 
 use bitmatch::bitmatch;
-use std::sync::mpsc::{self, TryRecvError, Receiver, Sender};
-use crate::renderer::*;
-use crate::events::*;
+use std::collections::VecDeque;
+use std::fmt;
+use crate::display::*;
+use crate::keypad::*;
+use crate::quirks::*;
 use crate::save::*;
 
 pub const RAM_SIZE: usize = 4096;
+pub const XO_RAM_SIZE: usize = 0x10000; // XO-CHIP has a 64 KiB address space
 pub const PROG_MEM_START_OFFSET: u16 = 0x200; // 0x000 to 0x1ff was reserved for interpreter
 pub const FONT_SPRITES_START_OFFSET: u16 = 0x050; // 0x050 to 0x9F
-const MAX_STACK_SIZE: usize = 32; // original chip8 only supported 16, so this should be good enough
+pub const BIG_FONT_SPRITES_START_OFFSET: u16 = 0x0A0; // 0x0A0 to 0x13F, SUPER-CHIP FX30
+pub const NUM_RPL_FLAGS: usize = 16; // HP48 RPL user flags for FX75/FX85 (SCHIP 1.1 only has 8)
+pub const AUDIO_PATTERN_SIZE: usize = 16; // XO-CHIP F002, 128 1-bit samples
+pub const DEFAULT_PITCH: u8 = 64; // XO-CHIP FX3A, 64 => 4000 Hz sample playback
+pub const MAX_STACK_SIZE: usize = 32; // original chip8 only supported 16, so this should be good enough
 const OPTYPE_MASK: u16 = 0xF000;
-const TIMER_CLOCK_SPEED: f32 = 60.0; // Hz
+pub const TIMER_CLOCK_SPEED: u32 = 60; // Hz
 static FONT_SPRITES: [u8; 80] =
 [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -32,28 +39,257 @@ static FONT_SPRITES: [u8; 80] =
     0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
     0xF0, 0x80, 0xF0, 0x80, 0x80  // F
 ];
+// 8x10 digits, SCHIP 1.1 only shipped 0-9, A-F are the ones Octo uses
+static BIG_FONT_SPRITES: [u8; 160] =
+[
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFE, 0xFF, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFF, 0xFE, // B
+    0x3E, 0x7F, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7F, 0x3E, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0  // F
+];
 
-pub static mut RAM: [u8; RAM_SIZE] = [0; RAM_SIZE];
-pub static mut TIMERS: [u8; 2] = [0; 2];
-
-#[derive(Debug, PartialEq)]
-// needed due to both ver having diff impl for some instr
+#[derive(Debug, Clone, Copy, PartialEq)]
+// instruction set, behaviour differences within it are in Quirks
 pub enum CPUMode
 {
     Chip8,
-    Chip48
+    Chip48,
+    SuperChip, // SCHIP 1.1
+    XoChip
+}
+
+impl CPUMode {
+    // SUPER-CHIP instructions (hires, scrolling, big sprites/font, RPL flags)
+    #[inline(always)]
+    pub fn has_schip_instrs(&self) -> bool {
+        *self == CPUMode::SuperChip || *self == CPUMode::XoChip
+    }
+
+    #[inline(always)]
+    pub fn has_xochip_instrs(&self) -> bool {
+        *self == CPUMode::XoChip
+    }
+}
+
+// splitmix64 scramble so small or similar seeds still start far apart (and never at 0, which xorshift can't leave)
+fn seed_to_rng_state(seed: u64) -> u64
+{
+    let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^= z >> 31;
+    return if z == 0 { 0x9E3779B97F4A7C15 } else { z };
 }
 
-enum Instructions
+// mode names as used on the command line
+pub fn parse_mode(name: &str) -> Option<CPUMode>
 {
-    CallMachineCode(u16), // 0NNN
-    ClearScreen, // 00E0
-    Return, // 00EE
-    Jump(u16), // 1NNN
-    SetVX(u16), // 6XNN
-    AddVX(u16), // 7XNN
-    SetIReg(u16), // ANNN
-    Draw(u16, u16, u16) // DXYN
+    match name {
+        "chip8" => Some(CPUMode::Chip8),
+        "chip48" => Some(CPUMode::Chip48),
+        "schip" => Some(CPUMode::SuperChip),
+        "xochip" => Some(CPUMode::XoChip),
+        _ => None
+    }
+}
+
+// mode as stored in save files
+pub fn mode_to_byte(mode: CPUMode) -> u8
+{
+    match mode {
+        CPUMode::Chip8 => 0,
+        CPUMode::Chip48 => 1,
+        CPUMode::SuperChip => 2,
+        CPUMode::XoChip => 3
+    }
+}
+
+pub fn mode_from_byte(byte: u8) -> Option<CPUMode>
+{
+    match byte {
+        0 => Some(CPUMode::Chip8),
+        1 => Some(CPUMode::Chip48),
+        2 => Some(CPUMode::SuperChip),
+        3 => Some(CPUMode::XoChip),
+        _ => None
+    }
+}
+
+// Decoded opcode, registers are 0x0 - 0xF
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instructions
+{
+    CallMachineCode(u16),   // 0NNN
+    ClearScreen,            // 00E0
+    Return,                 // 00EE
+    ScrollDown(u8),         // 00CN (SCHIP)
+    ScrollUp(u8),           // 00DN (XO-CHIP)
+    ScrollRight,            // 00FB (SCHIP)
+    ScrollLeft,             // 00FC (SCHIP)
+    Exit,                   // 00FD (SCHIP)
+    LoresMode,              // 00FE (SCHIP)
+    HiresMode,              // 00FF (SCHIP)
+    Jump(u16),              // 1NNN
+    Call(u16),              // 2NNN
+    SkipEqImm(u8, u8),      // 3XNN
+    SkipNeImm(u8, u8),      // 4XNN
+    SkipEqReg(u8, u8),      // 5XY0
+    SaveRange(u8, u8),      // 5XY2 (XO-CHIP)
+    LoadRange(u8, u8),      // 5XY3 (XO-CHIP)
+    SetVX(u8, u8),          // 6XNN
+    AddVX(u8, u8),          // 7XNN
+    Math(u8, u8, u8),       // 8XYN, N = 0-7 or E
+    SkipNeReg(u8, u8),      // 9XY0
+    SetIReg(u16),           // ANNN
+    JumpOffset(u16),        // BNNN (or BXNN)
+    Rand(u8, u8),           // CXNN
+    Draw(u8, u8, u8),       // DXYN
+    SkipKeyPressed(u8),     // EX9E
+    SkipKeyNotPressed(u8),  // EXA1
+    LoadLongI,              // F000 NNNN (XO-CHIP), NNNN is the next word
+    SelectPlanes(u8),       // FN01 (XO-CHIP)
+    LoadAudioPattern,       // F002 (XO-CHIP)
+    GetDelay(u8),           // FX07
+    WaitKey(u8),            // FX0A
+    SetDelay(u8),           // FX15
+    SetSound(u8),           // FX18
+    AddI(u8),               // FX1E
+    FontChar(u8),           // FX29
+    BigFontChar(u8),        // FX30 (SCHIP)
+    StoreBcd(u8),           // FX33
+    SetPitch(u8),           // FX3A (XO-CHIP)
+    StoreRegs(u8),          // FX55
+    LoadRegs(u8),           // FX65
+    SaveFlags(u8),          // FX75 (SCHIP)
+    LoadFlags(u8)           // FX85 (SCHIP)
+}
+
+// None if the opcode isn't an instruction in this mode
+#[bitmatch]
+pub fn decode(opcode: u16, mode: CPUMode) -> Option<Instructions>
+{
+    let schip = mode.has_schip_instrs();
+    let xochip = mode.has_xochip_instrs();
+
+    // every arm returns
+    #[bitmatch]
+    match opcode {
+        "0000_0000_1110_0000" => return Some(Instructions::ClearScreen),
+        "0000_0000_1110_1110" => return Some(Instructions::Return),
+        "0000_0000_1100_nnnn" if schip => return Some(Instructions::ScrollDown(n as u8)),
+        "0000_0000_1101_nnnn" if xochip => return Some(Instructions::ScrollUp(n as u8)),
+        "0000_0000_1111_1011" if schip => return Some(Instructions::ScrollRight),
+        "0000_0000_1111_1100" if schip => return Some(Instructions::ScrollLeft),
+        "0000_0000_1111_1101" if schip => return Some(Instructions::Exit),
+        "0000_0000_1111_1110" if schip => return Some(Instructions::LoresMode),
+        "0000_0000_1111_1111" if schip => return Some(Instructions::HiresMode),
+        "0000_nnnn_nnnn_nnnn" => return Some(Instructions::CallMachineCode(n)),
+        "0001_nnnn_nnnn_nnnn" => return Some(Instructions::Jump(n)),
+        "0010_nnnn_nnnn_nnnn" => return Some(Instructions::Call(n)),
+        "0011_xxxx_nnnn_nnnn" => return Some(Instructions::SkipEqImm(x as u8, n as u8)),
+        "0100_xxxx_nnnn_nnnn" => return Some(Instructions::SkipNeImm(x as u8, n as u8)),
+        "0101_xxxx_yyyy_0000" => return Some(Instructions::SkipEqReg(x as u8, y as u8)),
+        "0101_xxxx_yyyy_0010" if xochip => return Some(Instructions::SaveRange(x as u8, y as u8)),
+        "0101_xxxx_yyyy_0011" if xochip => return Some(Instructions::LoadRange(x as u8, y as u8)),
+        "0110_xxxx_nnnn_nnnn" => return Some(Instructions::SetVX(x as u8, n as u8)),
+        "0111_xxxx_nnnn_nnnn" => return Some(Instructions::AddVX(x as u8, n as u8)),
+        "1000_xxxx_yyyy_nnnn" if n <= 7 || n == 0xE => return Some(Instructions::Math(x as u8, y as u8, n as u8)),
+        "1001_xxxx_yyyy_0000" => return Some(Instructions::SkipNeReg(x as u8, y as u8)),
+        "1010_nnnn_nnnn_nnnn" => return Some(Instructions::SetIReg(n)),
+        "1011_nnnn_nnnn_nnnn" => return Some(Instructions::JumpOffset(n)),
+        "1100_xxxx_nnnn_nnnn" => return Some(Instructions::Rand(x as u8, n as u8)),
+        "1101_xxxx_yyyy_nnnn" => return Some(Instructions::Draw(x as u8, y as u8, n as u8)),
+        "1110_xxxx_1001_1110" => return Some(Instructions::SkipKeyPressed(x as u8)),
+        "1110_xxxx_1010_0001" => return Some(Instructions::SkipKeyNotPressed(x as u8)),
+        "1111_0000_0000_0000" if xochip => return Some(Instructions::LoadLongI),
+        "1111_xxxx_0000_0001" if xochip => return Some(Instructions::SelectPlanes(x as u8)),
+        "1111_0000_0000_0010" if xochip => return Some(Instructions::LoadAudioPattern),
+        "1111_xxxx_0000_0111" => return Some(Instructions::GetDelay(x as u8)),
+        "1111_xxxx_0000_1010" => return Some(Instructions::WaitKey(x as u8)),
+        "1111_xxxx_0001_0101" => return Some(Instructions::SetDelay(x as u8)),
+        "1111_xxxx_0001_1000" => return Some(Instructions::SetSound(x as u8)),
+        "1111_xxxx_0001_1110" => return Some(Instructions::AddI(x as u8)),
+        "1111_xxxx_0010_1001" => return Some(Instructions::FontChar(x as u8)),
+        "1111_xxxx_0011_0000" if schip => return Some(Instructions::BigFontChar(x as u8)),
+        "1111_xxxx_0011_0011" => return Some(Instructions::StoreBcd(x as u8)),
+        "1111_xxxx_0011_1010" if xochip => return Some(Instructions::SetPitch(x as u8)),
+        "1111_xxxx_0101_0101" => return Some(Instructions::StoreRegs(x as u8)),
+        "1111_xxxx_0110_0101" => return Some(Instructions::LoadRegs(x as u8)),
+        "1111_xxxx_0111_0101" if schip => return Some(Instructions::SaveFlags(x as u8)),
+        "1111_xxxx_1000_0101" if schip => return Some(Instructions::LoadFlags(x as u8)),
+        _ => return None
+    };
+}
+
+// bytes the instruction at addr takes up, F000 NNNN is the only 4 byte one
+#[inline(always)]
+pub fn instr_len(instr: &Instructions) -> u16 {
+    if *instr == Instructions::LoadLongI { 4 } else { 2 }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemAccessKind
+{
+    Read,
+    Write
+}
+
+// data access made by an instruction, recorded while memory tracing is on
+#[derive(Debug, Clone, Copy)]
+pub struct MemAccess {
+    pub kind: MemAccessKind,
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8 // same as old for reads
+}
+
+// why CPU::step couldn't execute the instruction at pc, the cpu is left as it was before it
+// so the frontend can decide to halt, pause in the debugger or skip it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuError
+{
+    UnknownOpcode { pc: u16, opcode: u16 },
+    StackOverflow { pc: u16 },
+    StackUnderflow { pc: u16 },
+    MemoryOutOfBounds { pc: u16, addr: usize },
+    MachineCodeCall { pc: u16, addr: u16 }, // 0NNN, no 1802/HP48 machine code to run
+    Exit { pc: u16 }                        // 00FD, the program ended itself
+}
+
+impl CpuError {
+
+    #[inline(always)]
+    pub fn pc(&self) -> u16 {
+        match *self {
+            CpuError::UnknownOpcode { pc, .. } | CpuError::StackOverflow { pc } | CpuError::StackUnderflow { pc }
+                | CpuError::MemoryOutOfBounds { pc, .. } | CpuError::MachineCodeCall { pc, .. } | CpuError::Exit { pc } => pc
+        }
+    }
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::UnknownOpcode { pc, opcode } => write!(f, "unknown opcode {:04X} at {:#05X}", opcode, pc),
+            CpuError::StackOverflow { pc } => write!(f, "stack overflow at {:#05X}, more than {} nested calls", pc, MAX_STACK_SIZE),
+            CpuError::StackUnderflow { pc } => write!(f, "stack underflow at {:#05X}, return without a call", pc),
+            CpuError::MemoryOutOfBounds { pc, addr } => write!(f, "memory access out of bounds at {:#05X} (address {:#06X})", pc, addr),
+            CpuError::MachineCodeCall { pc, addr } => write!(f, "machine code call to {:#05X} at {:#05X} isn't supported", addr, pc),
+            CpuError::Exit { pc } => write!(f, "program exited at {:#05X}", pc)
+        }
+    }
 }
 
 enum TimerRegs
@@ -68,7 +304,7 @@ enum RegisterRWMode
     Write
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CPU {
     pc: u16,
     I: u16,              // index register - points to loc in mem
@@ -76,6 +312,18 @@ pub struct CPU {
     stack: [u16; MAX_STACK_SIZE],
     sp: u8,              // stack pointer
     mode: CPUMode,
+    quirks: Quirks,
+    vblank_wait: bool,   // set by DXYN with the display_wait quirk, see take_vblank_wait()
+    key_wait: Option<u8>, // key latched by FX0A, waiting for it to be released (key_release quirk)
+    ram: Vec<u8>,        // always XO_RAM_SIZE, only the first mem_size() bytes are addressable
+    timers: [u8; 2],     // [delay, sound], ticked by tick_timers()
+    rpl_flags: [u8; NUM_RPL_FLAGS],
+    audio_pattern: [u8; AUDIO_PATTERN_SIZE],
+    pitch: u8,
+    mem_trace: Option<Vec<MemAccess>>, // Some while tracing, see set_mem_tracing()
+    rom_hash: Option<u32>,             // crc32 of the loaded rom, saves record it to catch mismatches
+    rng_state: u64,                    // CXNN xorshift state, seed_rng() makes runs reproducible
+    rng_script: VecDeque<u8>,          // values CXNN uses before falling back to the generator
 }
 
 impl CPU {
@@ -86,19 +334,26 @@ impl CPU {
         self.registers = [0; 16];
         self.stack = [0; MAX_STACK_SIZE];
         self.sp = 0;
+        self.ram = vec![0; XO_RAM_SIZE];
+        self.timers = [0; 2];
+        self.audio_pattern = [0; AUDIO_PATTERN_SIZE];
+        self.pitch = DEFAULT_PITCH;
+        self.key_wait = None;
+        self.rom_hash = None;
 
         // load font sprites
-        unsafe {
-            let mut write_ptr = FONT_SPRITES_START_OFFSET as usize;
-            for el in FONT_SPRITES {
-                RAM[write_ptr] = el;
-                write_ptr += 1;
-            }
-        }
+        let font_start = FONT_SPRITES_START_OFFSET as usize;
+        self.ram[font_start..font_start + FONT_SPRITES.len()].copy_from_slice(&FONT_SPRITES);
+        let big_font_start = BIG_FONT_SPRITES_START_OFFSET as usize;
+        self.ram[big_font_start..big_font_start + BIG_FONT_SPRITES.len()].copy_from_slice(&BIG_FONT_SPRITES);
     }
 
     pub fn save_state(&mut self, save: &mut Save)
     {
+        for byte in self.ram.iter() {
+            save.write(*byte);
+        }
+
         save.write((self.pc & 0xFF) as u8);
         save.write((self.pc >> 8) as u8);
         save.write((self.I & 0xFF) as u8);
@@ -113,40 +368,58 @@ impl CPU {
         }
 
         save.write(self.sp);
-        match self.mode {
-            CPUMode::Chip8 => save.write(0),
-            CPUMode::Chip48 => save.write(1)
+        save.write(mode_to_byte(self.mode));
+        save.write(self.quirks.to_bits());
+
+        save.write(self.timer(TimerRegs::Delay));
+        save.write(self.timer(TimerRegs::Sound));
+        for flag in self.rpl_flags {
+            save.write(flag);
         }
 
-        unsafe {
-            save.write(TIMERS[TimerRegs::Delay as usize]);
-            save.write(TIMERS[TimerRegs::Sound as usize]);
+        for sample in self.audio_pattern {
+            save.write(sample);
         }
+        save.write(self.pitch);
     }
 
-    pub fn load_state(&mut self, save: &mut Save)
+    pub fn load_state(&mut self, save: &mut Save) -> Result<(), String>
     {
-        self.pc = save.read_u16();
-        self.I = save.read_u16();
+        for byte in self.ram.iter_mut() {
+            *byte = save.read()?;
+        }
+
+        self.pc = save.read_u16()?;
+        self.I = save.read_u16()?;
         for i in 0..self.registers.len() {
-            self.registers[i] = save.read();
+            self.registers[i] = save.read()?;
         }
 
         for i in 0..self.stack.len() {
-            self.stack[i] = save.read_u16();
+            self.stack[i] = save.read_u16()?;
         }
 
-        self.sp = save.read();
-        match save.read() {
-            0 => self.mode = CPUMode::Chip8,
-            1 => self.mode = CPUMode::Chip48,
-            _ => panic!("Invalid CPU mode in save file")
+        self.sp = save.read()?;
+        if self.sp as usize > MAX_STACK_SIZE {
+            return Err(format!("stack pointer {} is past the end of the stack", self.sp));
         }
 
-        unsafe {
-            TIMERS[TimerRegs::Delay as usize] = save.read();
-            TIMERS[TimerRegs::Sound as usize] = save.read();
+        let mode_byte = save.read()?;
+        self.mode = mode_from_byte(mode_byte).ok_or(format!("invalid cpu mode {} in save", mode_byte))?;
+        self.quirks = Quirks::from_bits(save.read()?);
+
+        self.set_timer(TimerRegs::Delay, save.read()?);
+        self.set_timer(TimerRegs::Sound, save.read()?);
+        for flag in self.rpl_flags.iter_mut() {
+            *flag = save.read()?;
         }
+
+        for sample in self.audio_pattern.iter_mut() {
+            *sample = save.read()?;
+        }
+        self.pitch = save.read()?;
+        self.key_wait = None; // restored from the KEYW section if the save has one
+        return Ok(());
     }
 
     pub fn set_prog_counter(&mut self, pc: u16)
@@ -158,199 +431,591 @@ impl CPU {
         self.pc
     }
 
+    // also resets the quirks to the mode's preset
     pub fn set_mode(&mut self, mode: CPUMode)
     {
+        self.quirks = default_quirks(&mode);
         self.mode = mode;
     }
 
-    fn register_rw(&mut self, last_reg_num: usize, mode: RegisterRWMode) -> bool
+    #[inline(always)]
+    pub fn mode(&self) -> CPUMode {
+        self.mode
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks)
+    {
+        self.quirks = quirks;
+    }
+
+    #[inline(always)]
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    // true (once) if the last instruction has to wait for the next frame before execution continues
+    pub fn take_vblank_wait(&mut self) -> bool {
+        std::mem::take(&mut self.vblank_wait)
+    }
+
+    #[inline(always)]
+    fn has_schip_instrs(&self) -> bool {
+        self.mode.has_schip_instrs()
+    }
+
+    #[inline(always)]
+    fn has_xochip_instrs(&self) -> bool {
+        self.mode.has_xochip_instrs()
+    }
+
+    // addressable memory for the current mode
+    #[inline(always)]
+    pub fn mem_size(&self) -> usize {
+        if self.has_xochip_instrs() { XO_RAM_SIZE } else { RAM_SIZE }
+    }
+
+    #[inline(always)]
+    pub fn index_reg(&self) -> u16 {
+        self.I
+    }
+
+    #[inline(always)]
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.registers
+    }
+
+    #[inline(always)]
+    pub fn stack_pointer(&self) -> u8 {
+        self.sp
+    }
+
+    // only the live part of the stack, bottom first
+    #[inline(always)]
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    #[inline(always)]
+    pub fn delay_timer(&self) -> u8 {
+        self.timer(TimerRegs::Delay)
+    }
+
+    #[inline(always)]
+    pub fn sound_timer(&self) -> u8 {
+        self.timer(TimerRegs::Sound)
+    }
+
+    #[inline(always)]
+    pub fn is_sound_playing(&self) -> bool {
+        self.timer(TimerRegs::Sound) > 0
+    }
+
+    // XO-CHIP 1-bit audio samples (F002) and playback pitch (FX3A)
+    #[inline(always)]
+    pub fn audio_pattern(&self) -> &[u8; AUDIO_PATTERN_SIZE] {
+        &self.audio_pattern
+    }
+
+    #[inline(always)]
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    #[inline(always)]
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    #[inline(always)]
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    // copies the program into memory and points pc at it, false if it doesn't fit
+    pub fn load_program(&mut self, data: &[u8]) -> bool
+    {
+        let start = PROG_MEM_START_OFFSET as usize;
+        if start + data.len() > self.mem_size() {
+            return false;
+        }
+
+        self.ram[start..start + data.len()].copy_from_slice(data);
+        self.pc = PROG_MEM_START_OFFSET;
+        self.rom_hash = Some(crc32(data));
+        return true;
+    }
+
+    // FX0A: the lowest numbered key held is latched. With the key_release quirk the instruction finishes
+    // once that key is let go, like the COSMAC VIP, otherwise as soon as it's pressed. None keeps waiting
+    fn wait_key(&mut self, keypad: &Keypad) -> Option<u8>
+    {
+        if let Some(key) = self.key_wait {
+            if keypad.is_key_pressed(key) {
+                return None;
+            }
+            self.key_wait = None;
+            return Some(key);
+        }
+
+        let key = keypad.first_key_pressed()?;
+        if self.quirks.key_release {
+            self.key_wait = Some(key);
+            return None;
+        }
+
+        return Some(key);
+    }
+
+    // the FX0A latch in the save's KEYW section, 0xFF when nothing is latched
+    pub fn save_key_wait_state(&self, save: &mut Save)
+    {
+        save.write(self.key_wait.unwrap_or(0xFF));
+    }
+
+    pub fn load_key_wait_state(&mut self, save: &mut Save) -> Result<(), String>
+    {
+        let key = save.read()?;
+        self.key_wait = if (key as usize) < NUM_KEYS { Some(key) } else { None };
+        return Ok(());
+    }
+
+    // CXNN draws from a xorshift64* generator so the same seed always gives the same numbers
+    pub fn seed_rng(&mut self, seed: u64)
+    {
+        self.rng_state = seed_to_rng_state(seed);
+    }
+
+    #[inline(always)]
+    pub fn rng_state(&self) -> u64 {
+        self.rng_state
+    }
+
+    pub fn set_rng_state(&mut self, state: u64)
+    {
+        self.rng_state = if state == 0 { seed_to_rng_state(0) } else { state };
+    }
+
+    // CXNN returns these (masked with NN as usual) in order before drawing from the generator again,
+    // so a test can force a specific outcome
+    pub fn set_rng_script(&mut self, values: &[u8])
+    {
+        self.rng_script = values.iter().copied().collect();
+    }
+
+    #[inline(always)]
+    pub fn rng_script(&self) -> Vec<u8> {
+        self.rng_script.iter().copied().collect()
+    }
+
+    // generator state and whatever is left of the script, in the save's RNG section
+    pub fn save_rng_state(&self, save: &mut Save)
+    {
+        for byte in self.rng_state.to_le_bytes() {
+            save.write(byte);
+        }
+
+        save.write_u16(self.rng_script.len() as u16);
+        for value in self.rng_script.iter() {
+            save.write(*value);
+        }
+    }
+
+    pub fn load_rng_state(&mut self, save: &mut Save) -> Result<(), String>
+    {
+        let low = save.read_u32()? as u64;
+        let high = save.read_u32()? as u64;
+        self.set_rng_state(low | (high << 32));
+
+        let script_len = save.read_u16()?;
+        self.rng_script.clear();
+        for _ in 0..script_len {
+            self.rng_script.push_back(save.read()?);
+        }
+
+        return Ok(());
+    }
+
+    fn next_random(&mut self) -> u8
+    {
+        if let Some(value) = self.rng_script.pop_front() {
+            return value;
+        }
+
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        return (self.rng_state.wrapping_mul(0x2545F4914F6CDD1D) >> 56) as u8;
+    }
+
+    // crc32 of the rom loaded with load_program(), None if there isn't one (e.g. a test program)
+    #[inline(always)]
+    pub fn rom_hash(&self) -> Option<u32> {
+        self.rom_hash
+    }
+
+    pub fn set_rom_hash(&mut self, rom_hash: Option<u32>)
+    {
+        self.rom_hash = rom_hash;
+    }
+
+    // counts the delay & sound timers down by one, call at TIMER_CLOCK_SPEED
+    // (i.e. once per frame) so timing only depends on instructions executed
+    pub fn tick_timers(&mut self)
+    {
+        for timer in self.timers.iter_mut() {
+            *timer = timer.saturating_sub(1);
+        }
+    }
+
+    #[inline(always)]
+    fn timer(&self, timer: TimerRegs) -> u8 {
+        self.timers[timer as usize]
+    }
+
+    #[inline(always)]
+    fn set_timer(&mut self, timer: TimerRegs, value: u8) {
+        self.timers[timer as usize] = value;
+    }
+
+    fn register_rw(&mut self, last_reg_num: usize, mode: RegisterRWMode) -> Result<(), CpuError>
     {
         let mut mem_ptr = self.I as usize;
-        if mem_ptr + last_reg_num >= RAM_SIZE {
-            return false; // overflowing mem
+        if mem_ptr + last_reg_num >= self.mem_size() {
+            return Err(CpuError::MemoryOutOfBounds { pc: self.pc, addr: mem_ptr + last_reg_num });
         }
 
         for reg in 0..(last_reg_num + 1) {
-            unsafe {
-                match mode {
-                    RegisterRWMode::Write => RAM[mem_ptr] = self.registers[reg],
-                    RegisterRWMode::Read => self.registers[reg] = RAM[mem_ptr]
-                }
+            match mode {
+                RegisterRWMode::Write => self.write_mem(mem_ptr, self.registers[reg]),
+                RegisterRWMode::Read => self.registers[reg] = self.read_mem(mem_ptr)
             }
 
             mem_ptr += 1;
         }
 
-        if self.mode == CPUMode::Chip8 {
+        if self.quirks.load_store_inc_i {
             self.I = mem_ptr as u16;
         }
 
-        return true;
+        return Ok(());
+    }
+
+    // skips the next instruction, which on XO-CHIP may be the 4 byte F000 NNNN
+    fn skip_next_instr(&mut self)
+    {
+        let next = self.pc as usize + 2;
+        if self.has_xochip_instrs() && next + 1 < self.mem_size()
+            && self.ram[next] == 0xF0 && self.ram[next + 1] == 0x00 {
+            self.pc = self.pc.wrapping_add(4);
+        } else {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    // XO-CHIP 5XY2/5XY3: save/load VX..VY (either direction) at I, I is left unchanged
+    fn register_range_rw(&mut self, x: usize, y: usize, mode: RegisterRWMode) -> Result<(), CpuError>
+    {
+        let count = x.abs_diff(y) + 1;
+        if self.I as usize + count > self.mem_size() {
+            return Err(CpuError::MemoryOutOfBounds { pc: self.pc, addr: self.I as usize + count - 1 });
+        }
+
+        for offset in 0..count {
+            let reg = if x <= y { x + offset } else { x - offset };
+            let mem_ptr = self.I as usize + offset;
+            match mode {
+                RegisterRWMode::Write => self.write_mem(mem_ptr, self.registers[reg]),
+                RegisterRWMode::Read => self.registers[reg] = self.read_mem(mem_ptr)
+            }
+        }
+
+        return Ok(());
     }
 
-    fn stack_push(&mut self, n: u16)
+    fn stack_push(&mut self, n: u16) -> Result<(), CpuError>
     {
-        if self.sp >= MAX_STACK_SIZE as u8 {
-            panic!("Stack overflow: tried to push when stack was already full");
+        if self.sp as usize >= MAX_STACK_SIZE {
+            return Err(CpuError::StackOverflow { pc: self.pc });
         }
-        
+
         self.stack[self.sp as usize] = n;
         self.sp += 1;
+        return Ok(());
     }
 
-    fn stack_pop(&mut self) -> u16
+    fn stack_pop(&mut self) -> Result<u16, CpuError>
     {
         if self.sp == 0 {
-            panic!("Tried to pop from stack when it's already empty");
+            return Err(CpuError::StackUnderflow { pc: self.pc });
         }
 
         self.sp -= 1;
-        self.stack[self.sp as usize]
+        return Ok(self.stack[self.sp as usize]);
     }
 
-    // returns true if there was an error
-    #[bitmatch]
-    pub fn step(&mut self, renderer: &mut Renderer) -> bool {
-        unsafe {
-            // fetch
-            let opcode: u16 =
-                ((RAM[self.pc as usize] as u16) << 8) | (RAM[(self.pc + 1) as usize] as u16);
-
-            // decode & exec
-            #[bitmatch]
-            match opcode {
-                "1010_nnnn_nnnn_nnnn" => {
-                    self.I = n;
-                },
-                "0110_xxxx_nnnn_nnnn" => { // set Vx = n (6XNN)
-                    assert!(x <= 0xF);
-                    self.registers[x as usize] = n as u8;
-                },
-                "0111_xxxx_nnnn_nnnn" => { // set Vx = n (6XNN)
-                    assert!(x <= 0xF);
-                    self.registers[x as usize] = self.registers[x as usize].wrapping_add(n as u8);
-                },
-                "0001_nnnn_nnnn_nnnn" => { // jump
-                    self.pc = n - 2;
-                },
-                "1011_nnnn_nnnn_nnnn" => { // BNNN jump (or BXNN)
-                    let mut jump_reg: usize = 0;
-                    if self.mode == CPUMode::Chip48 {
-                        jump_reg = ((n >> 8) & 0x0F) as usize;
-                    }
+    // records every data read/write instructions make (not fetches) until turned off,
+    // collect them with take_mem_accesses()
+    pub fn set_mem_tracing(&mut self, enabled: bool)
+    {
+        if enabled != self.mem_trace.is_some() {
+            self.mem_trace = if enabled { Some(Vec::new()) } else { None };
+        }
+    }
 
-                    self.pc = n + (self.registers[jump_reg] as u16) - 2;
-                },
-                "0010_nnnn_nnnn_nnnn" => { // 2NNN => call subroutine
-                    self.stack_push(self.pc);
-                    self.pc = n - 2; // -2 since pc is incr at the end
-                },
-                "0000_0000_1110_1110" => { // return (00EE)
-                    self.pc = self.stack_pop();
-                },
-                "iiii_xxxx_nnnn_nnnn" if i == 3 || i == 4 => { // 3XNN or 4XNN
-                    let vx = self.registers[x as usize];
-                    if (i == 3 && vx == n as u8) || (i == 4 && vx != n as u8)
-                    {
-                        self.pc += 2;
-                    }
-                },
-                "iiii_xxxx_yyyy_0000" if i == 5 || i == 9 => { // 5XY0 or 9XY0
-                    let vx = self.registers[x as usize];
-                    let vy = self.registers[y as usize];
-                    if (i == 5 && vx == vy) || (i == 9 && vx != vy)
-                    {
-                        self.pc += 2;
-                    }
-                },
-                "1110_xxxx_iiii_iiii" if i == 0x9E || i == 0xA1 => { // skip if pressed
-                    let vx = self.registers[x as usize];
-                    assert!(vx <= 0xF);
-
-                    let pressed = renderer.is_key_pressed(vx);
-                    if (i == 0x9E && pressed) || (i == 0xA1 && !pressed) {
-                        self.pc += 2;
-                    }
-                },
-                "1000_xxxx_yyyy_nnnn" => { // 8XYN (math ops)
-                    if !self.handle_math_ops(x as usize, y as usize, n as u8) {
-                        return false;
-                    }
-                },
-                "1100_xxxx_nnnn_nnnn" => { // CXNN (rand)
-                    self.registers[x as usize] = rand::random::<u8>() & n as u8;
-                },
-                "1101_xxxx_yyyy_nnnn" => { // DXYN (draw)
-                    self.handle_draw(renderer, Instructions::Draw(x, y, n))
-                },
-                "0000_0000_1110_0000" => {
-                    renderer.clear_screen();
-                },
-                "1111_xxxx_iiii_iiii" => { // timers, add to index, get key
-                    match i {
-                        0x07 => self.registers[x as usize] = TIMERS[TimerRegs::Delay as usize],
-                        0x15 => TIMERS[TimerRegs::Delay as usize] = self.registers[x as usize],
-                        0x18 => TIMERS[TimerRegs::Sound as usize] = self.registers[x as usize],
-                        0x1E => {
-                            let overflow: bool;
-                            (self.I, overflow) = self.I.overflowing_add(self.registers[x as usize] as u16);
-                            if overflow || self.I >= RAM_SIZE as u16 {
-                                self.registers[0xF] = 1;
-                            }
-                        },
-                        0x0A => if renderer.is_any_key_pressed() {
-                            // TODO: support key released as well (original behaviour on COSMAC VIP)
-                            self.registers[x as usize] = renderer.get_first_key_pressed();
-                        } else {
-                            self.pc -= 2;
-                        },
-                        0x29 => self.I = FONT_SPRITES_START_OFFSET + ((self.registers[x as usize] as u16 & 0x0F) * 5),
-                        0x33 => if !self.write_decimal_at_I(x as usize) { return false; },
-                        0x55 => if !self.register_rw(x as usize, RegisterRWMode::Write) { return false; },
-                        0x65 => if !self.register_rw(x as usize, RegisterRWMode::Read) { return false; },
-                        _ => return false
-                    }
-                },
-                _ => return false,
-            }
+    pub fn take_mem_accesses(&mut self) -> Vec<MemAccess> {
+        match self.mem_trace.as_mut() {
+            Some(trace) => std::mem::take(trace),
+            None => Vec::new()
+        }
+    }
+
+    #[inline(always)]
+    fn read_mem(&mut self, addr: usize) -> u8 {
+        let value = self.ram[addr];
+        if let Some(trace) = self.mem_trace.as_mut() {
+            trace.push(MemAccess { kind: MemAccessKind::Read, addr: addr as u16, old: value, new: value });
+        }
 
-            self.pc += 2;
-            return true;
+        return value;
+    }
+
+    #[inline(always)]
+    fn write_mem(&mut self, addr: usize, value: u8) {
+        let old = self.ram[addr];
+        self.ram[addr] = value;
+        if let Some(trace) = self.mem_trace.as_mut() {
+            trace.push(MemAccess { kind: MemAccessKind::Write, addr: addr as u16, old, new: value });
         }
     }
 
-    fn handle_draw(&mut self, renderer: &mut Renderer, instr: Instructions) {
+    // big endian opcode at addr, bytes past the end of ram read as 0
+    pub fn opcode_at(&self, addr: u16) -> u16 {
+        let byte = |addr: usize| self.ram.get(addr).copied().unwrap_or(0) as u16;
+        (byte(addr as usize) << 8) | byte(addr as usize + 1)
+    }
+
+    // moves pc past the instruction at pc without executing it, e.g. to carry on after one errored
+    pub fn skip_instruction(&mut self)
+    {
+        let len = decode(self.opcode_at(self.pc), self.mode).map(|instr| instr_len(&instr)).unwrap_or(2);
+        self.pc = self.pc.wrapping_add(len);
+    }
+
+    // executes the instruction at pc, on error nothing is changed and pc still points at it
+    pub fn step(&mut self, display: &mut Display, keypad: &Keypad) -> Result<(), CpuError> {
+        // fetch
+        if self.pc as usize + 1 >= self.mem_size() {
+            return Err(CpuError::MemoryOutOfBounds { pc: self.pc, addr: self.pc as usize });
+        }
+        let opcode: u16 = self.opcode_at(self.pc);
+
+        // decode
+        let Some(instr) = decode(opcode, self.mode) else {
+            return Err(CpuError::UnknownOpcode { pc: self.pc, opcode });
+        };
+
+        // exec
+        match instr {
+            Instructions::SetIReg(n) => {
+                self.I = n;
+            },
+            Instructions::SetVX(x, n) => {
+                self.registers[x as usize] = n;
+            },
+            Instructions::AddVX(x, n) => {
+                self.registers[x as usize] = self.registers[x as usize].wrapping_add(n);
+            },
+            Instructions::Jump(n) => {
+                self.pc = n.wrapping_sub(2);
+            },
+            Instructions::JumpOffset(n) => { // BNNN jump (or BXNN)
+                let mut jump_reg: usize = 0;
+                if self.quirks.jump_vx {
+                    jump_reg = ((n >> 8) & 0x0F) as usize;
+                }
+
+                self.pc = (n + self.registers[jump_reg] as u16).wrapping_sub(2);
+            },
+            Instructions::Call(n) => {
+                self.stack_push(self.pc)?;
+                self.pc = n.wrapping_sub(2); // -2 since pc is incr at the end
+            },
+            Instructions::Return => {
+                self.pc = self.stack_pop()?;
+            },
+            Instructions::SkipEqImm(x, n) | Instructions::SkipNeImm(x, n) => {
+                let vx = self.registers[x as usize];
+                if (vx == n) == matches!(instr, Instructions::SkipEqImm(..)) {
+                    self.skip_next_instr();
+                }
+            },
+            Instructions::SkipEqReg(x, y) | Instructions::SkipNeReg(x, y) => {
+                let vx = self.registers[x as usize];
+                let vy = self.registers[y as usize];
+                if (vx == vy) == matches!(instr, Instructions::SkipEqReg(..)) {
+                    self.skip_next_instr();
+                }
+            },
+            Instructions::SaveRange(x, y) => self.register_range_rw(x as usize, y as usize, RegisterRWMode::Write)?,
+            Instructions::LoadRange(x, y) => self.register_range_rw(x as usize, y as usize, RegisterRWMode::Read)?,
+            Instructions::SkipKeyPressed(x) | Instructions::SkipKeyNotPressed(x) => {
+                let vx = self.registers[x as usize];
+                let pressed = keypad.is_key_pressed(vx);
+                if pressed == matches!(instr, Instructions::SkipKeyPressed(..)) {
+                    self.skip_next_instr();
+                }
+            },
+            Instructions::Math(x, y, n) => {
+                if !self.handle_math_ops(x as usize, y as usize, n) {
+                    return Err(CpuError::UnknownOpcode { pc: self.pc, opcode });
+                }
+            },
+            Instructions::Rand(x, n) => {
+                self.registers[x as usize] = self.next_random() & n;
+            },
+            Instructions::Draw(..) => {
+                self.handle_draw(display, instr)?;
+                self.vblank_wait = self.quirks.display_wait;
+            },
+            Instructions::ClearScreen => display.clear_screen(),
+            Instructions::ScrollDown(n) => display.scroll_down(self.scroll_distance(display, n as u32)),
+            Instructions::ScrollUp(n) => display.scroll_up(self.scroll_distance(display, n as u32)),
+            Instructions::ScrollRight => display.scroll_right(self.scroll_distance(display, 4)),
+            Instructions::ScrollLeft => display.scroll_left(self.scroll_distance(display, 4)),
+            Instructions::Exit => return Err(CpuError::Exit { pc: self.pc }), // 00FD exit interpreter
+            Instructions::LoresMode => display.set_hires(false),
+            Instructions::HiresMode => display.set_hires(true),
+            Instructions::CallMachineCode(addr) => return Err(CpuError::MachineCodeCall { pc: self.pc, addr }),
+            Instructions::LoadLongI => { // F000 NNNN, I = NNNN
+                let next = self.pc as usize + 2;
+                if next + 1 >= self.mem_size() {
+                    return Err(CpuError::MemoryOutOfBounds { pc: self.pc, addr: next + 1 });
+                }
+
+                self.I = self.opcode_at(next as u16);
+                self.pc += 2;
+            },
+            Instructions::SelectPlanes(n) => display.select_planes(n),
+            Instructions::LoadAudioPattern => {
+                let start = self.I as usize;
+                if start + AUDIO_PATTERN_SIZE > self.mem_size() {
+                    return Err(CpuError::MemoryOutOfBounds { pc: self.pc, addr: start + AUDIO_PATTERN_SIZE - 1 });
+                }
+
+                for offset in 0..AUDIO_PATTERN_SIZE {
+                    self.audio_pattern[offset] = self.read_mem(start + offset);
+                }
+            },
+            Instructions::SetPitch(x) => self.pitch = self.registers[x as usize],
+            Instructions::GetDelay(x) => self.registers[x as usize] = self.timer(TimerRegs::Delay),
+            Instructions::SetDelay(x) => self.set_timer(TimerRegs::Delay, self.registers[x as usize]),
+            Instructions::SetSound(x) => self.set_timer(TimerRegs::Sound, self.registers[x as usize]),
+            Instructions::AddI(x) => {
+                let overflow: bool;
+                (self.I, overflow) = self.I.overflowing_add(self.registers[x as usize] as u16);
+                if self.quirks.fx1e_vf {
+                    self.registers[0xF] = (overflow || self.I as usize >= self.mem_size()) as u8;
+                }
+            },
+            Instructions::WaitKey(x) => match self.wait_key(keypad) {
+                Some(key) => self.registers[x as usize] = key,
+                None => self.pc = self.pc.wrapping_sub(2)
+            },
+            Instructions::FontChar(x) => self.I = FONT_SPRITES_START_OFFSET + ((self.registers[x as usize] as u16 & 0x0F) * 5),
+            Instructions::BigFontChar(x) => self.I = BIG_FONT_SPRITES_START_OFFSET + ((self.registers[x as usize] as u16 & 0x0F) * 10),
+            Instructions::SaveFlags(x) => {
+                let count = (x as usize + 1).min(NUM_RPL_FLAGS);
+                self.rpl_flags[..count].copy_from_slice(&self.registers[..count]);
+            },
+            Instructions::LoadFlags(x) => {
+                let count = (x as usize + 1).min(NUM_RPL_FLAGS);
+                self.registers[..count].copy_from_slice(&self.rpl_flags[..count]);
+            },
+            Instructions::StoreBcd(x) => self.write_decimal_at_I(x as usize)?,
+            Instructions::StoreRegs(x) => self.register_rw(x as usize, RegisterRWMode::Write)?,
+            Instructions::LoadRegs(x) => self.register_rw(x as usize, RegisterRWMode::Read)?
+        }
+
+        self.pc = self.pc.wrapping_add(2);
+        return Ok(());
+    }
+
+    // scrolls are by hires pixels on SCHIP 1.1, so half as many lores pixels. XO-CHIP scrolls by
+    // pixels of the current resolution. the lores display can't hold half a pixel, so an odd 00CN
+    // drops it: 00C3 scrolls one lores pixel and 00C1 doesn't scroll at all (an approximation)
+    fn scroll_distance(&self, display: &Display, n: u32) -> u32 {
+        if self.mode == CPUMode::SuperChip && !display.is_hires() { n / 2 } else { n }
+    }
+
+    fn handle_draw(&mut self, display: &mut Display, instr: Instructions) -> Result<(), CpuError> {
         match instr {
             Instructions::Draw(x, y, n) => {
-                let sX = self.registers[x as usize] & ((DISPLAY_WIDTH - 1) as u8);
-                let sY = self.registers[y as usize] & ((DISPLAY_HEIGHT - 1) as u8);
+                let width = display.width();
+                let height = display.height();
+                let sX = self.registers[x as usize] as u32 & (width - 1);
+                let sY = self.registers[y as usize] as u32 & (height - 1);
+
+                // DXY0 on SUPER-CHIP draws a 16x16 sprite, 2 bytes per row
+                let big_sprite = n == 0 && self.has_schip_instrs();
+                let (rows, bytes_per_row) = if big_sprite { (16, 2) } else { (n as u32, 1) };
+
+                // check the sprite data fits before drawing any of it, rows clipped at the bottom aren't read
+                let planes = (0..NUM_PLANES).filter(|plane| display.is_plane_selected(*plane)).count();
+                let rows_read = if self.quirks.clip_sprites { rows.min(height - sY) } else { rows };
+                if planes > 0 && rows_read > 0 {
+                    let last_addr = self.I as usize + (planes - 1) * (rows * bytes_per_row) as usize + (rows_read * bytes_per_row) as usize - 1;
+                    if last_addr >= self.mem_size() {
+                        return Err(CpuError::MemoryOutOfBounds { pc: self.pc, addr: last_addr });
+                    }
+                }
+
                 self.registers[0xF] = 0; // VF = 0
 
-                let mut row = 0;
-                while row < n && row < DISPLAY_HEIGHT as u16 {
-                    let mut drawX = sX;
-                    unsafe {
-                        let mut sprite = RAM[(self.I + row) as usize];
-                        while sprite > 0 && drawX < DISPLAY_WIDTH as u8 {
-                            let pixel = (sprite & 0x80) > 0;
+                // with several XO-CHIP planes selected the sprite data for each plane follows the previous one
+                let mut sprite_addr = self.I as usize;
+                for plane in 0..NUM_PLANES {
+                    if !display.is_plane_selected(plane) {
+                        continue;
+                    }
+
+                    for row in 0..rows {
+                        let mut drawY = sY + row;
+                        if drawY >= height {
+                            if self.quirks.clip_sprites {
+                                break; // clipped at the bottom edge
+                            }
+                            drawY %= height;
+                        }
+
+                        let addr = sprite_addr + (row * bytes_per_row) as usize;
+                        let mut sprite: u16 = (self.read_mem(addr) as u16) << 8;
+                        if big_sprite {
+                            sprite |= self.read_mem(addr + 1) as u16;
+                        }
+
+                        let mut drawX = sX;
+                        while sprite > 0 && (drawX < width || !self.quirks.clip_sprites) {
+                            let pixel = (sprite & 0x8000) > 0;
                             if pixel {
-                                let pixel_unset = renderer.draw(drawX, sY + (row as u8));
+                                let pixel_unset = display.draw(plane, (drawX % width) as u8, drawY as u8);
                                 if pixel_unset {
                                     self.registers[0xF] = 1; // VF = 1
                                 }
                             }
 
-                            sprite = sprite << 1;
+                            sprite <<= 1;
                             drawX += 1;
                         }
                     }
-                    
-                    row += 1;
+
+                    sprite_addr += (rows * bytes_per_row) as usize;
                 }
             }
             _ => panic!("handle_draw: called with invalid instr\n")
         }
-    } 
+
+        return Ok(());
+    }
 
     fn handle_math_ops(&mut self, regX: usize, regY: usize, op: u8) -> bool
     {
@@ -362,9 +1027,17 @@ impl CPU {
         let overflow: bool;
         match op {
             0 => { self.registers[regX] = vy; },
-            1 => { self.registers[regX] = vx | vy; },
-            2 => { self.registers[regX] = vx & vy; },
-            3 => { self.registers[regX] = vx ^ vy; },
+            1..=3 => {
+                self.registers[regX] = match op {
+                    1 => vx | vy,
+                    2 => vx & vy,
+                    _ => vx ^ vy
+                };
+
+                if self.quirks.vf_reset {
+                    self.registers[0xF] = 0;
+                }
+            },
             4 => {
                 (self.registers[regX], overflow) = vx.overflowing_add(vy);
                 self.registers[0xF] = overflow as u8;
@@ -378,7 +1051,7 @@ impl CPU {
                 self.registers[0xF] = !overflow as u8;
             },
             6 | 0xE => {
-                if self.mode == CPUMode::Chip8 {
+                if self.quirks.shift_vy {
                     vx = vy;
                 }
 
@@ -400,61 +1073,25 @@ impl CPU {
         return true;
     }
 
-    fn write_decimal_at_I(&mut self, reg: usize) -> bool
+    fn write_decimal_at_I(&mut self, reg: usize) -> Result<(), CpuError>
     {
-        if self.I + 2 >= RAM_SIZE as u16
+        if self.I as usize + 2 >= self.mem_size()
         {
-            return false;
+            return Err(CpuError::MemoryOutOfBounds { pc: self.pc, addr: self.I as usize + 2 });
         }
 
         let mut intval = self.registers[reg];
-        unsafe {
-            RAM[self.I as usize + 2] = intval % 10;
-            intval /= 10;
-            RAM[self.I as usize + 1] = intval % 10;
-            intval /= 10;
-            RAM[self.I as usize] = intval % 10;
-        }
+        self.write_mem(self.I as usize + 2, intval % 10);
+        intval /= 10;
+        self.write_mem(self.I as usize + 1, intval % 10);
+        intval /= 10;
+        self.write_mem(self.I as usize, intval % 10);
 
-        return true;
+        return Ok(());
     }
 
 }
 
-unsafe fn timer_ticker(rx: Receiver<SystemEvent>)
-{
-    let mut paused = false;
-    loop {
-        match rx.try_recv() {
-            Ok(event) => {
-                println!("TimerThread: recv event {:?}", event);
-                match event {
-                    SystemEvent::Pause => paused = true,
-                    SystemEvent::Resume => paused = false,
-                    SystemEvent::Exit => break,
-                    SystemEvent::Save => {},
-                    _ => {}
-                }
-            }
-            Err(TryRecvError::Disconnected) => {
-                println!("TimerThread: comms channel disconnected, exiting");
-                break;
-            }
-            Err(TryRecvError::Empty) => {}
-        }
-
-        if !paused && TIMERS[TimerRegs::Delay as usize] > 0 {
-            TIMERS[TimerRegs::Delay as usize] -= 1;
-        }
-
-        if !paused && TIMERS[TimerRegs::Sound as usize] > 0 {
-            TIMERS[TimerRegs::Sound as usize] -= 1;
-        }
-
-        std::thread::sleep(std::time::Duration::from_secs_f32( 1.0 / TIMER_CLOCK_SPEED ));
-    }
-}
-
 pub fn make_cpu() -> CPU {
     CPU {
         pc: 0,
@@ -462,13 +1099,18 @@ pub fn make_cpu() -> CPU {
         registers: [0; 16],
         sp: 0,
         stack: [0; MAX_STACK_SIZE],
-        mode: CPUMode::Chip8
+        mode: CPUMode::Chip8,
+        quirks: VIP_QUIRKS,
+        vblank_wait: false,
+        key_wait: None,
+        ram: vec![0; XO_RAM_SIZE],
+        timers: [0; 2],
+        rpl_flags: [0; NUM_RPL_FLAGS],
+        audio_pattern: [0; AUDIO_PATTERN_SIZE],
+        pitch: DEFAULT_PITCH,
+        mem_trace: None,
+        rom_hash: None,
+        rng_state: seed_to_rng_state(rand::random::<u64>()),
+        rng_script: VecDeque::new()
     }
 }
-
-pub fn start_timer_thread() -> Sender<SystemEvent>
-{
-    let (tx, rx) = mpsc::channel::<SystemEvent>();
-    std::thread::spawn(|| unsafe { timer_ticker(rx); });
-    return tx;
-}
@@ -0,0 +1,515 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use crate::cpu::*;
+use crate::machine::*;
+
+// Opcode pattern for breakpoints, 4 hex digits where '.', 'x' or '?' match any
+// nibble, e.g. "D..." (any draw) or "2xxx" (any call)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpcodePattern {
+    mask: u16,
+    value: u16
+}
+
+impl OpcodePattern {
+    #[inline(always)]
+    pub fn matches(&self, opcode: u16) -> bool {
+        opcode & self.mask == self.value
+    }
+}
+
+impl fmt::Display for OpcodePattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for nibble in (0..4).rev() {
+            let shift = nibble * 4;
+            if (self.mask >> shift) & 0xF == 0 {
+                write!(f, ".")?;
+            } else {
+                write!(f, "{:X}", (self.value >> shift) & 0xF)?;
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+pub fn parse_opcode_pattern(text: &str) -> Result<OpcodePattern, String>
+{
+    if text.chars().count() != 4 {
+        return Err(format!("opcode pattern '{}' must be 4 characters, e.g. D... or 00EE", text));
+    }
+
+    let mut pattern = OpcodePattern { mask: 0, value: 0 };
+    for ch in text.chars() {
+        pattern.mask <<= 4;
+        pattern.value <<= 4;
+        match ch {
+            '.' | 'x' | 'X' | '?' => {},
+            _ => match ch.to_digit(16) {
+                Some(digit) => {
+                    pattern.mask |= 0xF;
+                    pattern.value |= digit as u16;
+                },
+                None => return Err(format!("invalid character '{}' in opcode pattern '{}'", ch, text))
+            }
+        }
+    }
+
+    return Ok(pattern);
+}
+
+// accepts 0x200, $200 or plain hex 200
+pub fn parse_addr(text: &str) -> Result<u16, String>
+{
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid address '{}', expected hex e.g. 0x200", text))
+}
+
+// Pauses on reads/writes of, or execution from, an inclusive address range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    start: u16,
+    end: u16,
+    read: bool,
+    write: bool,
+    execute: bool
+}
+
+impl Watchpoint {
+    #[inline(always)]
+    fn contains(&self, addr: u16) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+
+    fn catches(&self, access: &MemAccess) -> bool {
+        let kind_watched = match access.kind {
+            MemAccessKind::Read => self.read,
+            MemAccessKind::Write => self.write
+        };
+
+        return kind_watched && self.contains(access.addr);
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#05X}-{:#05X}:", self.start, self.end)?;
+        if self.read { write!(f, "r")?; }
+        if self.write { write!(f, "w")?; }
+        if self.execute { write!(f, "x")?; }
+        return Ok(());
+    }
+}
+
+// <start>[-<end>][:<rwx>], e.g. 0x300 (writes to one byte), 0x300-0x3FF:rw or 200-2ff:x
+pub fn parse_watchpoint(text: &str) -> Result<Watchpoint, String>
+{
+    let (range, kinds) = text.split_once(':').unwrap_or((text, "w"));
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_addr(start)?, parse_addr(end)?),
+        None => {
+            let addr = parse_addr(range)?;
+            (addr, addr)
+        }
+    };
+
+    if start > end {
+        return Err(format!("watchpoint range '{}' ends before it starts", range));
+    }
+
+    if kinds.is_empty() || kinds.chars().any(|ch| !"rwx".contains(ch)) {
+        return Err(format!("invalid watchpoint kinds '{}', expected any of r, w and x", kinds));
+    }
+
+    return Ok(Watchpoint {
+        start,
+        end,
+        read: kinds.contains('r'),
+        write: kinds.contains('w'),
+        execute: kinds.contains('x')
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunMode
+{
+    Running,
+    Paused,
+    StepOver { return_addr: u16, sp: u8 }, // run until the called subroutine returns here
+    StepOut { sp: u8 }                     // run until the stack drops below sp
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DebuggerAction
+{
+    Resume,
+    Quit
+}
+
+enum CommandResult
+{
+    Stay,
+    Resume,
+    Quit
+}
+
+// Breakpoints and stepping on top of Machine::step_in_frame, controlled from a
+// stdin console while paused. Frontend agnostic, used by both the SDL and headless builds.
+pub struct Debugger {
+    breakpoints: Vec<u16>,
+    opcode_breakpoints: Vec<OpcodePattern>,
+    watchpoints: Vec<Watchpoint>,
+    break_on_invalid: bool,
+    run_mode: RunMode,
+    resume_pc: Option<u16>, // don't break again on the instruction we stopped at
+    last_command: String
+}
+
+impl Debugger {
+
+    pub fn add_breakpoint(&mut self, addr: u16)
+    {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn add_opcode_breakpoint(&mut self, pattern: OpcodePattern)
+    {
+        if !self.opcode_breakpoints.contains(&pattern) {
+            self.opcode_breakpoints.push(pattern);
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint)
+    {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn set_break_on_invalid(&mut self, enabled: bool)
+    {
+        self.break_on_invalid = enabled;
+    }
+
+    pub fn pause(&mut self)
+    {
+        self.run_mode = RunMode::Paused;
+    }
+
+    #[inline(always)]
+    pub fn is_paused(&self) -> bool {
+        self.run_mode == RunMode::Paused
+    }
+
+    // Runs the rest of the current frame like Machine::run_frame, stopping early on a
+    // breakpoint or when a step over/out completes.
+    // Err if the program exited, or the cpu hit an error and break on invalid instruction is off
+    pub fn run_frame(&mut self, machine: &mut Machine) -> Result<(), CpuError>
+    {
+        self.update_mem_tracing(machine);
+        while !self.is_paused() {
+            let pc = machine.cpu().prog_counter();
+            let opcode = machine.cpu().opcode_at(pc);
+
+            if self.resume_pc.take() != Some(pc) {
+                if self.breakpoints.contains(&pc) {
+                    self.stop(machine, &format!("breakpoint at {:#05X}", pc));
+                    return Ok(());
+                }
+
+                if let Some(pattern) = self.opcode_breakpoints.iter().find(|pattern| pattern.matches(opcode)) {
+                    self.stop(machine, &format!("opcode {:04X} matches breakpoint {}", opcode, pattern));
+                    return Ok(());
+                }
+
+                if let Some(watch) = self.watchpoints.iter().find(|watch| watch.execute && watch.contains(pc)) {
+                    self.stop(machine, &format!("watchpoint {} executing {:04X} at {:#05X}", watch, opcode, pc));
+                    return Ok(());
+                }
+            }
+
+            let result = machine.step_in_frame();
+            if let StepResult::Error(err) = result {
+                // 00FD ends the run, there's nothing to debug
+                if !self.break_on_invalid || matches!(err, CpuError::Exit { .. }) {
+                    return Err(err);
+                }
+
+                self.stop(machine, &err.to_string());
+                return Ok(());
+            }
+
+            // data watchpoints stop after the instruction, so the new values can be shown
+            let accesses = machine.cpu_mut().take_mem_accesses();
+            let hits: Vec<String> = accesses.iter()
+                .filter_map(|access| self.watchpoints.iter().find(|watch| watch.catches(access)).map(|watch| (watch, access)))
+                .map(|(watch, access)| format_watch_hit(watch, access))
+                .collect();
+            if !hits.is_empty() {
+                self.stop(machine, &format!("watchpoint hit by {:04X} at {:#05X}\n{}", opcode, pc, hits.join("\n")));
+                return Ok(());
+            }
+
+            let cpu = machine.cpu();
+            match self.run_mode {
+                RunMode::StepOver { return_addr, sp } if cpu.prog_counter() == return_addr && cpu.stack_pointer() == sp => {
+                    self.stop(machine, "stepped over call");
+                },
+                RunMode::StepOut { sp } if cpu.stack_pointer() < sp => {
+                    self.stop(machine, "stepped out of subroutine");
+                },
+                _ => {}
+            }
+
+            if result == StepResult::FrameDone {
+                break;
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn stop(&mut self, machine: &Machine, reason: &str)
+    {
+        self.run_mode = RunMode::Paused;
+        println!("[debugger] paused: {}", reason);
+        print!("{}", format_cpu_state(machine.cpu()));
+    }
+
+    // Reads commands from stdin until one resumes execution. 'frame_input' runs before every
+    // instruction the console steps, so the frontend can feed in a movie's keypad state
+    pub fn run_console(&mut self, machine: &mut Machine, frame_input: &mut dyn FnMut(&mut Machine)) -> DebuggerAction
+    {
+        let stdin = io::stdin();
+        loop {
+            print!("(chip8 dbg) ");
+            io::stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                return DebuggerAction::Quit; // stdin closed
+            }
+
+            match self.execute_command(machine, line.trim(), frame_input) {
+                CommandResult::Stay => {},
+                CommandResult::Resume => {
+                    self.resume_pc = Some(machine.cpu().prog_counter());
+                    return DebuggerAction::Resume;
+                },
+                CommandResult::Quit => return DebuggerAction::Quit
+            }
+        }
+    }
+
+    fn execute_command(&mut self, machine: &mut Machine, line: &str, frame_input: &mut dyn FnMut(&mut Machine)) -> CommandResult
+    {
+        // an empty line repeats the last command, handy for stepping
+        let line = if line.is_empty() { self.last_command.clone() } else { line.to_string() };
+        self.last_command = line.clone();
+
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let arg = words.next();
+
+        match (command, arg) {
+            ("c" | "continue", _) => {
+                self.run_mode = RunMode::Running;
+                return CommandResult::Resume;
+            },
+            ("s" | "step", _) => {
+                let count = arg.and_then(|n| n.parse::<u32>().ok()).unwrap_or(1);
+                for _ in 0..count {
+                    if !self.single_step(machine, frame_input) {
+                        break;
+                    }
+                }
+                print!("{}", format_cpu_state(machine.cpu()));
+            },
+            ("n" | "next", _) => {
+                let cpu = machine.cpu();
+                let pc = cpu.prog_counter();
+                if cpu.opcode_at(pc) & 0xF000 == 0x2000 {
+                    self.run_mode = RunMode::StepOver { return_addr: pc.wrapping_add(2), sp: cpu.stack_pointer() };
+                    return CommandResult::Resume;
+                }
+
+                self.single_step(machine, frame_input);
+                print!("{}", format_cpu_state(machine.cpu()));
+            },
+            ("o" | "out", _) => {
+                let sp = machine.cpu().stack_pointer();
+                if sp == 0 {
+                    println!("not in a subroutine");
+                } else {
+                    self.run_mode = RunMode::StepOut { sp };
+                    return CommandResult::Resume;
+                }
+            },
+            ("b" | "break", Some(addr)) => match parse_addr(addr) {
+                Ok(addr) => self.add_breakpoint(addr),
+                Err(err) => println!("{}", err)
+            },
+            ("bo" | "break-op", Some(pattern)) => match parse_opcode_pattern(pattern) {
+                Ok(pattern) => self.add_opcode_breakpoint(pattern),
+                Err(err) => println!("{}", err)
+            },
+            ("w" | "watch", Some(spec)) => match parse_watchpoint(spec) {
+                Ok(watchpoint) => self.add_watchpoint(watchpoint),
+                Err(err) => println!("{}", err)
+            },
+            ("d" | "delete", Some(target)) => {
+                let addr = parse_addr(target).ok();
+                let pattern = parse_opcode_pattern(target).ok();
+                self.breakpoints.retain(|bp| Some(*bp) != addr);
+                self.opcode_breakpoints.retain(|bp| Some(*bp) != pattern);
+                self.watchpoints.retain(|watch| Some(watch.start) != addr);
+            },
+            ("l" | "list", _) => {
+                for bp in self.breakpoints.iter() {
+                    println!("pc {:#05X}", bp);
+                }
+                for bp in self.opcode_breakpoints.iter() {
+                    println!("opcode {}", bp);
+                }
+                for watch in self.watchpoints.iter() {
+                    println!("watch {}", watch);
+                }
+                println!("break on invalid instruction: {}", if self.break_on_invalid { "on" } else { "off" });
+            },
+            ("invalid", Some(value)) => self.break_on_invalid = value == "on",
+            ("r" | "regs", _) => print!("{}", format_cpu_state(machine.cpu())),
+            ("m" | "mem", Some(addr)) => match parse_addr(addr) {
+                Ok(addr) => {
+                    let len = words.next().and_then(|n| n.parse::<usize>().ok()).unwrap_or(16);
+                    print!("{}", format_memory(machine.cpu(), addr as usize, len));
+                },
+                Err(err) => println!("{}", err)
+            },
+            ("q" | "quit", _) => return CommandResult::Quit,
+            _ => print_help()
+        }
+
+        return CommandResult::Stay;
+    }
+
+    // Asks for one watchpoint on stdin, for a hotkey while the emulator keeps running
+    pub fn prompt_watchpoint(&mut self)
+    {
+        print!("watch (<start>[-<end>][:rwx]): ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line).unwrap_or(0);
+        if line.trim().is_empty() {
+            return;
+        }
+
+        match parse_watchpoint(line.trim()) {
+            Ok(watchpoint) => {
+                println!("[debugger] watching {}", watchpoint);
+                self.add_watchpoint(watchpoint);
+            },
+            Err(err) => println!("{}", err)
+        }
+    }
+
+    // data reads and writes are only traced while there are watchpoints for them
+    fn update_mem_tracing(&self, machine: &mut Machine)
+    {
+        let watching_data = self.watchpoints.iter().any(|watch| watch.read || watch.write);
+        machine.cpu_mut().set_mem_tracing(watching_data);
+    }
+
+    // returns false if the instruction errored
+    fn single_step(&mut self, machine: &mut Machine, frame_input: &mut dyn FnMut(&mut Machine)) -> bool
+    {
+        frame_input(machine);
+        // a watchpoint may have been added from the console since the last frame
+        self.update_mem_tracing(machine);
+        if let StepResult::Error(err) = machine.step_in_frame() {
+            println!("{}", err);
+            return false;
+        }
+
+        for access in machine.cpu_mut().take_mem_accesses() {
+            if let Some(watch) = self.watchpoints.iter().find(|watch| watch.catches(&access)) {
+                println!("{}", format_watch_hit(watch, &access));
+            }
+        }
+
+        return true;
+    }
+}
+
+fn print_help()
+{
+    println!("commands (empty line repeats the last one):");
+    println!("  c, continue          resume execution");
+    println!("  s, step [n]          execute n (default 1) instructions");
+    println!("  n, next              step over a 2NNN call");
+    println!("  o, out               run until the current subroutine returns (00EE)");
+    println!("  b, break <addr>      break when pc reaches addr (hex)");
+    println!("  bo, break-op <pat>   break on opcodes matching pat, e.g. D... or 00EE");
+    println!("  w, watch <range>     pause on memory access, <start>[-<end>][:rwx] e.g. 0x300-0x30F:w");
+    println!("  d, delete <addr|pat> remove a breakpoint, or the watchpoints starting at addr");
+    println!("  l, list              list breakpoints and watchpoints");
+    println!("  invalid on|off       break on cpu errors instead of leaving them to --on-error");
+    println!("  r, regs              show cpu state");
+    println!("  m, mem <addr> [len]  dump memory");
+    println!("  q, quit              exit the emulator");
+}
+
+pub fn format_cpu_state(cpu: &CPU) -> String
+{
+    let pc = cpu.prog_counter();
+    let mut out = format!(
+        "pc: {:#05X}  opcode: {:04X}  I: {:#05X}  sp: {}  DT: {}  ST: {}\n",
+        pc, cpu.opcode_at(pc), cpu.index_reg(), cpu.stack_pointer(), cpu.delay_timer(), cpu.sound_timer()
+    );
+
+    for (reg, value) in cpu.registers().iter().enumerate() {
+        out += &format!("V{:X}: {:02X}{}", reg, value, if reg % 8 == 7 { "\n" } else { "  " });
+    }
+
+    let stack: Vec<String> = cpu.stack().iter().map(|addr| format!("{:#05X}", addr)).collect();
+    out += &format!("stack: [{}]\n", stack.join(", "));
+    return out;
+}
+
+fn format_watch_hit(watch: &Watchpoint, access: &MemAccess) -> String
+{
+    match access.kind {
+        MemAccessKind::Read => format!("  {} read {:#05X}: {:02X}", watch, access.addr, access.old),
+        MemAccessKind::Write => format!("  {} wrote {:#05X}: {:02X} -> {:02X}", watch, access.addr, access.old, access.new)
+    }
+}
+
+fn format_memory(cpu: &CPU, addr: usize, len: usize) -> String
+{
+    let ram = cpu.ram();
+    let end = (addr + len).min(cpu.mem_size());
+    let mut out = String::new();
+    for line_start in (addr..end).step_by(16) {
+        let bytes: Vec<String> = ram[line_start..(line_start + 16).min(end)].iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        out += &format!("{:#06X}: {}\n", line_start, bytes.join(" "));
+    }
+
+    return out;
+}
+
+pub fn make_debugger() -> Debugger
+{
+    Debugger {
+        breakpoints: Vec::new(),
+        opcode_breakpoints: Vec::new(),
+        watchpoints: Vec::new(),
+        break_on_invalid: true,
+        run_mode: RunMode::Running,
+        resume_pc: None,
+        last_command: String::new()
+    }
+}
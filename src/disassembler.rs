@@ -0,0 +1,282 @@
+use std::collections::{BTreeSet, HashSet};
+use crate::cpu::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax
+{
+    Octo,   // e.g. "v0 := 0x12", "sprite v0 v1 5"
+    Classic // Cowgod style, e.g. "LD V0, 0x12", "DRW V0, V1, 5"
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisasmLine {
+    pub addr: u16,
+    pub bytes: Vec<u8>,      // raw opcode (2 or 4 bytes), or up to 2 bytes of data
+    pub label: Option<String>,
+    pub text: String,        // mnemonic, or the bytes as data
+    pub is_data: bool        // never reached from the entry point or not an instruction in this mode
+}
+
+// Disassembles a rom loaded at PROG_MEM_START_OFFSET. Code is found by following
+// jumps, calls and skips from the entry point, everything else (sprites, tables,
+// code only reachable through BNNN) is flagged as data.
+pub fn disassemble(rom: &[u8], mode: CPUMode, syntax: Syntax) -> Vec<DisasmLine>
+{
+    let base = PROG_MEM_START_OFFSET;
+    // anything past the top of the address space can't be loaded
+    let rom = &rom[..rom.len().min(0x10000 - base as usize)];
+    let end = base as usize + rom.len();
+    let word_at = |addr: u16| -> Option<u16> {
+        if addr < base {
+            return None;
+        }
+        let offset = (addr - base) as usize;
+        if offset + 1 >= rom.len() {
+            return None;
+        }
+        Some(((rom[offset] as u16) << 8) | rom[offset + 1] as u16)
+    };
+
+    // trace reachable code and collect jump / call targets for labels
+    let mut code: HashSet<u16> = HashSet::new();
+    let mut targets: BTreeSet<u16> = BTreeSet::new();
+    let mut pending: Vec<u16> = vec![base];
+    while let Some(addr) = pending.pop() {
+        if addr < base || addr as usize + 1 >= end || code.contains(&addr) {
+            continue;
+        }
+
+        let Some(instr) = word_at(addr).and_then(|opcode| decode(opcode, mode)) else {
+            continue;
+        };
+        // a 2 word instruction cut off by the end of the rom is data
+        if instr_len(&instr) == 4 && addr.checked_add(2).and_then(word_at).is_none() {
+            continue;
+        }
+
+        code.insert(addr);
+        // None past the top of the address space
+        let next = addr.checked_add(instr_len(&instr));
+        match instr {
+            Instructions::Jump(n) => {
+                targets.insert(n);
+                pending.push(n);
+            },
+            Instructions::Call(n) => {
+                targets.insert(n);
+                pending.push(n);
+                pending.extend(next);
+            },
+            Instructions::SkipEqImm(..) | Instructions::SkipNeImm(..) | Instructions::SkipEqReg(..)
+                | Instructions::SkipNeReg(..) | Instructions::SkipKeyPressed(..) | Instructions::SkipKeyNotPressed(..) => {
+                pending.extend(next);
+                let skipped_len = next.and_then(word_at)
+                    .and_then(|opcode| decode(opcode, mode))
+                    .map(|skipped| instr_len(&skipped))
+                    .unwrap_or(2);
+                pending.extend(next.and_then(|next| next.checked_add(skipped_len)));
+            },
+            Instructions::SetIReg(n) => {
+                targets.insert(n);
+                pending.extend(next);
+            },
+            Instructions::Return | Instructions::Exit | Instructions::JumpOffset(_) => {},
+            _ => pending.extend(next)
+        }
+    }
+
+    // lay out code and data first, only addresses that start a line can be labelled
+    let mut layout: Vec<(u16, u16, Option<Instructions>)> = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let addr = base + offset as u16;
+        let instr = if code.contains(&addr) { word_at(addr).and_then(|opcode| decode(opcode, mode)) } else { None };
+        let len = match instr {
+            Some(instr) => instr_len(&instr),
+            None => {
+                // data goes out in pairs, a single byte if the next one is code or a label
+                let pair = offset + 1 < rom.len() && !code.contains(&(addr + 1)) && !targets.contains(&(addr + 1));
+                if pair { 2 } else { 1 }
+            }
+        };
+
+        layout.push((addr, len, instr));
+        offset += len as usize;
+    }
+
+    targets.retain(|target| layout.iter().any(|&(addr, _, _)| addr == *target));
+
+    let mut lines: Vec<DisasmLine> = Vec::new();
+    for (addr, len, instr) in layout {
+        let offset = (addr - base) as usize;
+        let bytes = rom[offset..(offset + len as usize).min(rom.len())].to_vec();
+        let label = if targets.contains(&addr) { Some(label_name(addr)) } else { None };
+        let text = match instr {
+            Some(instr) => format_instr(&instr, addr.checked_add(2).and_then(word_at).unwrap_or(0), &targets, syntax),
+            None => format_data(&bytes, syntax)
+        };
+
+        lines.push(DisasmLine { addr, bytes, label, text, is_data: instr.is_none() });
+    }
+
+    return lines;
+}
+
+// address, raw bytes and mnemonic per line, labels on their own line
+pub fn format_listing(lines: &[DisasmLine], syntax: Syntax) -> String
+{
+    let mut out = String::new();
+    for line in lines {
+        if let Some(label) = &line.label {
+            out += &match syntax {
+                Syntax::Octo => format!(": {}\n", label),
+                Syntax::Classic => format!("{}:\n", label)
+            };
+        }
+
+        let raw: String = line.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let comment = match syntax { Syntax::Octo => "#", Syntax::Classic => ";" };
+        let data_note = if line.is_data { format!("  {} data", comment) } else { String::new() };
+        out += &format!("{:#05X}  {:<8}  {}{}\n", line.addr, raw, line.text, data_note);
+    }
+
+    return out;
+}
+
+fn label_name(addr: u16) -> String
+{
+    format!("label_{:03x}", addr)
+}
+
+fn format_addr(addr: u16, targets: &BTreeSet<u16>) -> String
+{
+    if targets.contains(&addr) { label_name(addr) } else { format!("{:#05X}", addr) }
+}
+
+fn format_data(bytes: &[u8], syntax: Syntax) -> String
+{
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:#04X}", byte)).collect();
+    match syntax {
+        Syntax::Octo => bytes.join(" "),
+        Syntax::Classic => format!("DB {}", bytes.join(", "))
+    }
+}
+
+fn format_instr(instr: &Instructions, long_addr: u16, targets: &BTreeSet<u16>, syntax: Syntax) -> String
+{
+    match syntax {
+        Syntax::Octo => format_octo(instr, long_addr, targets),
+        Syntax::Classic => format_classic(instr, long_addr, targets)
+    }
+}
+
+fn format_octo(instr: &Instructions, long_addr: u16, targets: &BTreeSet<u16>) -> String
+{
+    match *instr {
+        Instructions::CallMachineCode(n) => format!("0x{:02X} 0x{:02X} # machine code call", n >> 8, n & 0xFF),
+        Instructions::ClearScreen => "clear".to_string(),
+        Instructions::Return => "return".to_string(),
+        Instructions::ScrollDown(n) => format!("scroll-down {}", n),
+        Instructions::ScrollUp(n) => format!("scroll-up {}", n),
+        Instructions::ScrollRight => "scroll-right".to_string(),
+        Instructions::ScrollLeft => "scroll-left".to_string(),
+        Instructions::Exit => "exit".to_string(),
+        Instructions::LoresMode => "lores".to_string(),
+        Instructions::HiresMode => "hires".to_string(),
+        Instructions::Jump(n) => format!("jump {}", format_addr(n, targets)),
+        Instructions::Call(n) => format!(":call {}", format_addr(n, targets)),
+        // Octo's 'if' runs the next instruction when the condition holds, so skips invert
+        Instructions::SkipEqImm(x, n) => format!("if v{:x} != {:#04X} then", x, n),
+        Instructions::SkipNeImm(x, n) => format!("if v{:x} == {:#04X} then", x, n),
+        Instructions::SkipEqReg(x, y) => format!("if v{:x} != v{:x} then", x, y),
+        Instructions::SkipNeReg(x, y) => format!("if v{:x} == v{:x} then", x, y),
+        Instructions::SkipKeyPressed(x) => format!("if v{:x} -key then", x),
+        Instructions::SkipKeyNotPressed(x) => format!("if v{:x} key then", x),
+        Instructions::SaveRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+        Instructions::LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+        Instructions::SetVX(x, n) => format!("v{:x} := {:#04X}", x, n),
+        Instructions::AddVX(x, n) => format!("v{:x} += {:#04X}", x, n),
+        Instructions::Math(x, y, op) => {
+            let operator = match op {
+                0 => ":=", 1 => "|=", 2 => "&=", 3 => "^=", 4 => "+=",
+                5 => "-=", 6 => ">>=", 7 => "=-", _ => "<<="
+            };
+            format!("v{:x} {} v{:x}", x, operator, y)
+        },
+        Instructions::SetIReg(n) => format!("i := {}", format_addr(n, targets)),
+        Instructions::JumpOffset(n) => format!("jump0 {}", format_addr(n, targets)),
+        Instructions::Rand(x, n) => format!("v{:x} := random {:#04X}", x, n),
+        Instructions::Draw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+        Instructions::LoadLongI => format!("i := long {}", format_addr(long_addr, targets)),
+        Instructions::SelectPlanes(n) => format!("plane {}", n),
+        Instructions::LoadAudioPattern => "audio".to_string(),
+        Instructions::GetDelay(x) => format!("v{:x} := delay", x),
+        Instructions::WaitKey(x) => format!("v{:x} := key", x),
+        Instructions::SetDelay(x) => format!("delay := v{:x}", x),
+        Instructions::SetSound(x) => format!("buzzer := v{:x}", x),
+        Instructions::AddI(x) => format!("i += v{:x}", x),
+        Instructions::FontChar(x) => format!("i := hex v{:x}", x),
+        Instructions::BigFontChar(x) => format!("i := bighex v{:x}", x),
+        Instructions::StoreBcd(x) => format!("bcd v{:x}", x),
+        Instructions::SetPitch(x) => format!("pitch := v{:x}", x),
+        Instructions::StoreRegs(x) => format!("save v{:x}", x),
+        Instructions::LoadRegs(x) => format!("load v{:x}", x),
+        Instructions::SaveFlags(x) => format!("saveflags v{:x}", x),
+        Instructions::LoadFlags(x) => format!("loadflags v{:x}", x)
+    }
+}
+
+fn format_classic(instr: &Instructions, long_addr: u16, targets: &BTreeSet<u16>) -> String
+{
+    match *instr {
+        Instructions::CallMachineCode(n) => format!("SYS {:#05X}", n),
+        Instructions::ClearScreen => "CLS".to_string(),
+        Instructions::Return => "RET".to_string(),
+        Instructions::ScrollDown(n) => format!("SCD {}", n),
+        Instructions::ScrollUp(n) => format!("SCU {}", n),
+        Instructions::ScrollRight => "SCR".to_string(),
+        Instructions::ScrollLeft => "SCL".to_string(),
+        Instructions::Exit => "EXIT".to_string(),
+        Instructions::LoresMode => "LOW".to_string(),
+        Instructions::HiresMode => "HIGH".to_string(),
+        Instructions::Jump(n) => format!("JP {}", format_addr(n, targets)),
+        Instructions::Call(n) => format!("CALL {}", format_addr(n, targets)),
+        Instructions::SkipEqImm(x, n) => format!("SE V{:X}, {:#04X}", x, n),
+        Instructions::SkipNeImm(x, n) => format!("SNE V{:X}, {:#04X}", x, n),
+        Instructions::SkipEqReg(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        Instructions::SkipNeReg(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        Instructions::SkipKeyPressed(x) => format!("SKP V{:X}", x),
+        Instructions::SkipKeyNotPressed(x) => format!("SKNP V{:X}", x),
+        Instructions::SaveRange(x, y) => format!("SAVE V{:X}-V{:X}", x, y),
+        Instructions::LoadRange(x, y) => format!("LOAD V{:X}-V{:X}", x, y),
+        Instructions::SetVX(x, n) => format!("LD V{:X}, {:#04X}", x, n),
+        Instructions::AddVX(x, n) => format!("ADD V{:X}, {:#04X}", x, n),
+        Instructions::Math(x, y, op) => {
+            let mnemonic = match op {
+                0 => "LD", 1 => "OR", 2 => "AND", 3 => "XOR", 4 => "ADD",
+                5 => "SUB", 6 => "SHR", 7 => "SUBN", _ => "SHL"
+            };
+            format!("{} V{:X}, V{:X}", mnemonic, x, y)
+        },
+        Instructions::SetIReg(n) => format!("LD I, {}", format_addr(n, targets)),
+        Instructions::JumpOffset(n) => format!("JP V0, {}", format_addr(n, targets)),
+        Instructions::Rand(x, n) => format!("RND V{:X}, {:#04X}", x, n),
+        Instructions::Draw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        Instructions::LoadLongI => format!("LD I, LONG {}", format_addr(long_addr, targets)),
+        Instructions::SelectPlanes(n) => format!("PLANE {}", n),
+        Instructions::LoadAudioPattern => "AUDIO".to_string(),
+        Instructions::GetDelay(x) => format!("LD V{:X}, DT", x),
+        Instructions::WaitKey(x) => format!("LD V{:X}, K", x),
+        Instructions::SetDelay(x) => format!("LD DT, V{:X}", x),
+        Instructions::SetSound(x) => format!("LD ST, V{:X}", x),
+        Instructions::AddI(x) => format!("ADD I, V{:X}", x),
+        Instructions::FontChar(x) => format!("LD F, V{:X}", x),
+        Instructions::BigFontChar(x) => format!("LD HF, V{:X}", x),
+        Instructions::StoreBcd(x) => format!("LD B, V{:X}", x),
+        Instructions::SetPitch(x) => format!("PITCH V{:X}", x),
+        Instructions::StoreRegs(x) => format!("LD [I], V{:X}", x),
+        Instructions::LoadRegs(x) => format!("LD V{:X}, [I]", x),
+        Instructions::SaveFlags(x) => format!("LD R, V{:X}", x),
+        Instructions::LoadFlags(x) => format!("LD V{:X}, R", x)
+    }
+}
@@ -0,0 +1,215 @@
+use crate::save::*;
+
+pub const DISPLAY_WIDTH: u32 = 64;
+pub const DISPLAY_HEIGHT: u32 = 32;
+pub const HIRES_DISPLAY_WIDTH: u32 = 128; // SUPER-CHIP 00FF
+pub const HIRES_DISPLAY_HEIGHT: u32 = 64;
+pub const DISPLAY_REFRESH_RATE: f32 = 60.0; // Hz
+pub const NUM_PLANES: usize = 2; // XO-CHIP bitplanes, a pixel's colour is (plane 1 bit << 1) | plane 0 bit
+
+const TEXT_ART_CHARS: [char; 4] = ['.', '#', '+', '@'];
+
+type Plane = [u128; HIRES_DISPLAY_HEIGHT as usize];
+
+// Framebuffer the CPU draws into, one u128 bitfield per row (bit N = column N) for each plane.
+// Frontends read it to present a frame, it knows nothing about windows.
+// In lores mode only the top-left DISPLAY_WIDTH x DISPLAY_HEIGHT pixels are used.
+#[derive(Clone)]
+pub struct Display {
+    pixel_buffer: [Plane; NUM_PLANES],
+    hires: bool,
+    selected_planes: u8 // bitmask, XO-CHIP FN01. Draw, clear and scroll only touch these
+}
+
+impl Display {
+
+    pub fn save_state(&mut self, save: &mut Save)
+    {
+        save.write(self.hires as u8);
+        save.write(self.selected_planes);
+        for plane in self.pixel_buffer {
+            for row in plane {
+                let mut r = row;
+                for _ in 0..16 {
+                    let byte: u8 = (r & 0xFF) as u8;
+                    save.write(byte);
+                    r >>= 8;
+                }
+            }
+        }
+    }
+
+    pub fn load_state(&mut self, save: &mut Save) -> Result<(), String>
+    {
+        self.hires = save.read()? > 0;
+        self.selected_planes = save.read()?;
+        for plane in self.pixel_buffer.iter_mut() {
+            for row in plane.iter_mut() {
+                let mut r: u128 = 0;
+                for j in 0..16 {
+                    r |= (save.read()? as u128) << (j * 8);
+                }
+
+                *row = r;
+            }
+        }
+
+        return Ok(());
+    }
+
+    // flips the pixel on the given plane, returns true if it was set before (i.e. it got turned off)
+    pub fn draw(&mut self, plane: usize, x: u8, y: u8) -> bool {
+        let (row, col) = (y as usize, x as usize);
+        if plane >= NUM_PLANES || row >= self.height() as usize || col >= self.width() as usize
+        {
+            return false;
+        }
+
+        let curr_pixel = (self.pixel_buffer[plane][row] >> col) & 1;
+
+        if (curr_pixel ^ 1) > 0 {
+            self.pixel_buffer[plane][row] |= 1 << col;
+        } else {
+            self.pixel_buffer[plane][row] &= !(1 << col);
+        }
+
+        return curr_pixel > 0;
+    }
+
+    // clears the selected planes
+    pub fn clear_screen(&mut self) {
+        for plane in 0..NUM_PLANES {
+            if self.is_plane_selected(plane) {
+                self.pixel_buffer[plane] = [0; HIRES_DISPLAY_HEIGHT as usize];
+            }
+        }
+    }
+
+    // switching resolution clears every plane
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.pixel_buffer = [[0; HIRES_DISPLAY_HEIGHT as usize]; NUM_PLANES];
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        self.selected_planes = planes & ((1 << NUM_PLANES) - 1);
+    }
+
+    #[inline(always)]
+    pub fn is_plane_selected(&self, plane: usize) -> bool {
+        (self.selected_planes >> plane) & 1 > 0
+    }
+
+    #[inline(always)]
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    #[inline(always)]
+    pub fn width(&self) -> u32 {
+        if self.hires { HIRES_DISPLAY_WIDTH } else { DISPLAY_WIDTH }
+    }
+
+    #[inline(always)]
+    pub fn height(&self) -> u32 {
+        if self.hires { HIRES_DISPLAY_HEIGHT } else { DISPLAY_HEIGHT }
+    }
+
+    // scrolls are in pixels of the current resolution and only move the selected planes
+    pub fn scroll_down(&mut self, n: u32) {
+        let height = self.height() as usize;
+        let n = (n as usize).min(height);
+        for plane in self.selected_planes_mut() {
+            plane.copy_within(0..height - n, n);
+            plane[..n].fill(0);
+        }
+    }
+
+    pub fn scroll_up(&mut self, n: u32) {
+        let height = self.height() as usize;
+        let n = (n as usize).min(height);
+        for plane in self.selected_planes_mut() {
+            plane.copy_within(n..height, 0);
+            plane[height - n..height].fill(0);
+        }
+    }
+
+    pub fn scroll_right(&mut self, n: u32) {
+        let mask = self.row_mask();
+        let height = self.height() as usize;
+        for plane in self.selected_planes_mut() {
+            for row in plane[..height].iter_mut() {
+                *row = (*row << n) & mask;
+            }
+        }
+    }
+
+    pub fn scroll_left(&mut self, n: u32) {
+        let height = self.height() as usize;
+        for plane in self.selected_planes_mut() {
+            for row in plane[..height].iter_mut() {
+                *row >>= n;
+            }
+        }
+    }
+
+    fn selected_planes_mut(&mut self) -> impl Iterator<Item = &mut Plane> {
+        let selected_planes = self.selected_planes;
+        self.pixel_buffer.iter_mut()
+            .enumerate()
+            .filter(move |(plane, _)| (selected_planes >> plane) & 1 > 0)
+            .map(|(_, rows)| rows)
+    }
+
+    fn row_mask(&self) -> u128 {
+        if self.width() >= u128::BITS { u128::MAX } else { (1 << self.width()) - 1 }
+    }
+
+    // colour index 0-3, one bit per plane
+    pub fn pixel_color(&self, x: u32, y: u32) -> u8 {
+        if y >= self.height() || x >= self.width() {
+            return 0;
+        }
+
+        let mut color = 0;
+        for plane in 0..NUM_PLANES {
+            color |= (((self.pixel_buffer[plane][y as usize] >> x) & 1) as u8) << plane;
+        }
+
+        return color;
+    }
+
+    #[inline(always)]
+    pub fn is_pixel_set(&self, x: u32, y: u32) -> bool {
+        self.pixel_color(x, y) > 0
+    }
+
+    // rows of the given plane in the current resolution
+    #[inline(always)]
+    pub fn rows(&self, plane: usize) -> &[u128] {
+        &self.pixel_buffer[plane][..self.height() as usize]
+    }
+
+    // one char per pixel, '.' for unset ones, '#' for plane 0, '+' for plane 1 and '@' for both.
+    // one line per row
+    pub fn to_text_art(&self) -> String {
+        let mut out = String::with_capacity(((self.width() + 1) * self.height()) as usize);
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                out.push(TEXT_ART_CHARS[self.pixel_color(x, y) as usize]);
+            }
+            out.push('\n');
+        }
+
+        return out;
+    }
+}
+
+pub fn make_display() -> Display
+{
+    Display {
+        pixel_buffer: [[0; HIRES_DISPLAY_HEIGHT as usize]; NUM_PLANES],
+        hires: false,
+        selected_planes: 1
+    }
+}
@@ -1,10 +1,14 @@
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum SystemEvent
 {
-    Pause = 0,
+    Pause,
     Resume,
-    Save,
+    SaveSlot(u8), // quick-save to slot 1 - 9
+    LoadSlot(u8), // quick-load from slot 1 - 9
     Exit,
+    Debug, // break into the debugger console
+    Watch, // prompt for a memory watchpoint
+    ToggleFullscreen,
 
     None
 }
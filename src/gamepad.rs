@@ -0,0 +1,105 @@
+use crate::events::SystemEvent;
+use crate::keymap::*;
+use crate::keypad::Keypad;
+
+// Game controller input. Frontends turn their controller events into GamepadEvents, this maps the
+// buttons to chip8 keys and hotkeys through the KeyBindings and keeps track of what's held down, so
+// it can be driven by synthetic events (see tests/gamepad_tests.rs).
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GamepadEvent {
+    Connected(u32),          // controller id
+    Disconnected(u32),
+    ButtonDown(u32, String), // controller id, SDL button name (e.g. "dpup")
+    ButtonUp(u32, String)
+}
+
+pub struct Gamepads {
+    buttons: Vec<(String, KeyAction)>,
+    connected: Vec<u32>,
+    held: Vec<(u32, String)> // buttons held down, by controller
+}
+
+impl Gamepads {
+
+    // presses and releases chip8 keys, returns the hotkey's event for hotkey buttons. 'keyboard_held'
+    // says whether the keyboard is holding a chip8 key down, a released button leaves those pressed
+    pub fn handle_event(&mut self, event: &GamepadEvent, keypad: &mut Keypad, keyboard_held: impl Fn(u8) -> bool) -> SystemEvent
+    {
+        match event {
+            GamepadEvent::Connected(id) => {
+                if !self.connected.contains(id) {
+                    self.connected.push(*id);
+                }
+            }
+            GamepadEvent::Disconnected(id) => {
+                // unplugging with a button down doesn't leave its key stuck
+                self.connected.retain(|connected| connected != id);
+                let released: Vec<String> = self.held.iter().filter(|(pad, _)| pad == id).map(|(_, button)| button.clone()).collect();
+                for button in released {
+                    self.release(*id, &button, keypad, &keyboard_held);
+                }
+            }
+            GamepadEvent::ButtonDown(id, button) => {
+                if self.held.iter().any(|(pad, held)| pad == id && held == button) {
+                    return SystemEvent::None;
+                }
+                self.held.push((*id, button.clone()));
+
+                match self.action(button) {
+                    Some(KeyAction::Keypad(key)) => keypad.set_key(key, true),
+                    Some(KeyAction::Hotkey(hotkey)) => return hotkey.event(false),
+                    None => {}
+                }
+            }
+            GamepadEvent::ButtonUp(id, button) => self.release(*id, button, keypad, &keyboard_held)
+        }
+
+        return SystemEvent::None;
+    }
+
+    // the chip8 key stays pressed while another button bound to it (on any controller) or a key is held
+    fn release(&mut self, id: u32, button: &str, keypad: &mut Keypad, keyboard_held: &impl Fn(u8) -> bool)
+    {
+        self.held.retain(|(pad, held)| !(*pad == id && held == button));
+        if let Some(KeyAction::Keypad(key)) = self.action(button) {
+            if !self.is_key_held(key) && !keyboard_held(key) {
+                keypad.set_key(key, false);
+            }
+        }
+    }
+
+    fn action(&self, button: &str) -> Option<KeyAction>
+    {
+        self.buttons.iter().find(|(bound, _)| bound == button).map(|(_, action)| *action)
+    }
+
+    fn is_action_held(&self, action: KeyAction) -> bool
+    {
+        self.held.iter().any(|(_, button)| self.action(button) == Some(action))
+    }
+
+    #[inline(always)]
+    pub fn is_key_held(&self, key: u8) -> bool {
+        self.is_action_held(KeyAction::Keypad(key))
+    }
+
+    #[inline(always)]
+    pub fn is_rewind_held(&self) -> bool {
+        self.is_action_held(KeyAction::Hotkey(Hotkey::Rewind))
+    }
+
+    #[inline(always)]
+    pub fn connected(&self) -> &[u32] {
+        &self.connected
+    }
+}
+
+pub fn make_gamepads(bindings: &KeyBindings) -> Gamepads
+{
+    Gamepads {
+        buttons: bindings.gamepad.clone(),
+        connected: Vec::new(),
+        held: Vec::new()
+    }
+}
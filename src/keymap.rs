@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::events::SystemEvent;
+use crate::keypad::NUM_KEYS;
+
+// Keyboard and game controller bindings for the keypad and the emulator hotkeys. Keys are SDL scancode
+// names and buttons SDL game controller button names, kept as text so the core doesn't depend on SDL,
+// the renderer looks them up. Any number of keys or buttons can be bound to the same action, see
+// chip8::config for how they're read from the settings file.
+
+// keyboard key for chip8 keys 0 - F, the 1234/QWER/ASDF/ZXCV block laid out like the COSMAC VIP keypad
+pub const DEFAULT_KEYS: [&str; NUM_KEYS] = ["X", "1", "2", "3", "Q", "W", "E", "A", "S", "D", "Z", "C", "4", "R", "F", "V"];
+const DEFAULT_SLOT_KEYS: [&str; 9] = ["F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9"];
+
+// SDL game controller button names (Xbox layout: a is the bottom face button)
+pub const GAMEPAD_BUTTONS: [&str; 21] = [
+    "a", "b", "x", "y", "back", "guide", "start", "leftstick", "rightstick", "leftshoulder", "rightshoulder",
+    "dpup", "dpdown", "dpleft", "dpright", "misc1", "paddle1", "paddle2", "paddle3", "paddle4", "touchpad"
+];
+// the d-pad on the 5/7/8/9 cross most roms steer with, face buttons on 6 and 4
+const DEFAULT_GAMEPAD: [(&str, &str); 10] = [
+    ("dpup", "5"), ("dpleft", "7"), ("dpdown", "8"), ("dpright", "9"), ("a", "6"), ("b", "4"),
+    ("start", "pause"), ("back", "rewind"), ("leftshoulder", "slot1"), ("rightshoulder", "load1")
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+    Quit,
+    Pause,
+    Debug,   // break into the debugger console
+    Watch,   // prompt for a memory watchpoint
+    Rewind,      // held down
+    Fullscreen,
+    Slot(u8),    // save to slot 1 - 9, load with Shift held
+    LoadSlot(u8) // load slot 1 - 9, for controllers which have no Shift
+}
+
+pub const HOTKEYS: [Hotkey; 24] = [
+    Hotkey::Quit, Hotkey::Pause, Hotkey::Debug, Hotkey::Watch, Hotkey::Rewind, Hotkey::Fullscreen,
+    Hotkey::Slot(1), Hotkey::Slot(2), Hotkey::Slot(3), Hotkey::Slot(4), Hotkey::Slot(5),
+    Hotkey::Slot(6), Hotkey::Slot(7), Hotkey::Slot(8), Hotkey::Slot(9),
+    Hotkey::LoadSlot(1), Hotkey::LoadSlot(2), Hotkey::LoadSlot(3), Hotkey::LoadSlot(4), Hotkey::LoadSlot(5),
+    Hotkey::LoadSlot(6), Hotkey::LoadSlot(7), Hotkey::LoadSlot(8), Hotkey::LoadSlot(9)
+];
+
+impl Hotkey {
+
+    // name in the [hotkeys] section of the settings file
+    pub fn name(&self) -> String {
+        match self {
+            Hotkey::Quit => "quit".to_string(),
+            Hotkey::Pause => "pause".to_string(),
+            Hotkey::Debug => "debug".to_string(),
+            Hotkey::Watch => "watch".to_string(),
+            Hotkey::Rewind => "rewind".to_string(),
+            Hotkey::Fullscreen => "fullscreen".to_string(),
+            Hotkey::Slot(slot) => format!("slot{}", slot),
+            Hotkey::LoadSlot(slot) => format!("load{}", slot)
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Hotkey> {
+        HOTKEYS.iter().find(|hotkey| hotkey.name() == name).copied()
+    }
+
+    // keyboard key, the load hotkeys have none (Shift + the slot key does the same)
+    pub fn default_key(&self) -> Option<&'static str> {
+        match self {
+            Hotkey::Quit => Some("Escape"),
+            Hotkey::Pause => Some("P"),
+            Hotkey::Debug => Some("B"),
+            Hotkey::Watch => Some("M"),
+            Hotkey::Rewind => Some("Backspace"),
+            Hotkey::Fullscreen => Some("F11"),
+            Hotkey::Slot(slot) => Some(DEFAULT_SLOT_KEYS[*slot as usize - 1]),
+            Hotkey::LoadSlot(_) => None
+        }
+    }
+
+    // what pressing it does, rewinding is checked every frame instead (see the renderer)
+    pub fn event(&self, shift: bool) -> SystemEvent {
+        match self {
+            Hotkey::Quit => SystemEvent::Exit,
+            Hotkey::Pause => SystemEvent::Pause,
+            Hotkey::Debug => SystemEvent::Debug,
+            Hotkey::Watch => SystemEvent::Watch,
+            Hotkey::Rewind => SystemEvent::None,
+            Hotkey::Fullscreen => SystemEvent::ToggleFullscreen,
+            Hotkey::Slot(slot) => if shift { SystemEvent::LoadSlot(*slot) } else { SystemEvent::SaveSlot(*slot) },
+            Hotkey::LoadSlot(slot) => SystemEvent::LoadSlot(*slot)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyAction {
+    Keypad(u8), // chip8 key 0 - F
+    Hotkey(Hotkey)
+}
+
+impl KeyAction {
+
+    // a chip8 key 0 - F or a hotkey name, e.g. "5" or "pause"
+    pub fn from_name(name: &str) -> Option<KeyAction> {
+        let chip8_key = u8::from_str_radix(name, 16).ok().filter(|key| name.len() == 1 && (*key as usize) < NUM_KEYS);
+        chip8_key.map(KeyAction::Keypad).or_else(|| Hotkey::from_name(name).map(KeyAction::Hotkey))
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            KeyAction::Keypad(key) => format!("{:X}", key),
+            KeyAction::Hotkey(hotkey) => hotkey.name()
+        }
+    }
+}
+
+impl fmt::Display for KeyAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyAction::Keypad(key) => write!(f, "chip8 key {:X}", key),
+            KeyAction::Hotkey(hotkey) => write!(f, "the {} hotkey", hotkey.name())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyBindings {
+    pub keypad: [Vec<String>; NUM_KEYS], // keyboard keys for chip8 keys 0 - F
+    pub hotkeys: Vec<(Hotkey, Vec<String>)>, // in HOTKEYS order
+    pub gamepad: Vec<(String, KeyAction)>     // controller button => action, each button does one thing
+}
+
+impl KeyBindings {
+
+    // replaces the keys bound to 'action', an empty list unbinds it
+    pub fn bind(&mut self, action: KeyAction, keys: Vec<String>) -> Result<(), String>
+    {
+        if keys.iter().any(|key| key.trim().is_empty()) {
+            return Err(format!("empty keyboard key name for {}", action));
+        }
+
+        match action {
+            KeyAction::Keypad(key) => {
+                let slot = self.keypad.get_mut(key as usize).ok_or(format!("there's no chip8 key {:X}", key))?;
+                *slot = keys;
+            }
+            KeyAction::Hotkey(hotkey) => match self.hotkeys.iter_mut().find(|(bound, _)| *bound == hotkey) {
+                Some((_, slot)) => *slot = keys,
+                None => self.hotkeys.push((hotkey, keys))
+            }
+        }
+
+        return Ok(());
+    }
+
+    // None unbinds the button
+    pub fn bind_button(&mut self, button: &str, action: Option<KeyAction>) -> Result<(), String>
+    {
+        if !GAMEPAD_BUTTONS.contains(&button) {
+            return Err(format!("unknown controller button '{}', expected one of {}", button, GAMEPAD_BUTTONS.join(", ")));
+        }
+
+        let index = self.gamepad.iter().position(|(bound, _)| bound == button);
+        match (index, action) {
+            (Some(index), Some(action)) => self.gamepad[index].1 = action,
+            (Some(index), None) => { self.gamepad.remove(index); },
+            (None, Some(action)) => self.gamepad.push((button.to_string(), action)),
+            (None, None) => {}
+        }
+
+        return Ok(());
+    }
+
+    pub fn button_action(&self, button: &str) -> Option<KeyAction>
+    {
+        self.gamepad.iter().find(|(bound, _)| bound == button).map(|(_, action)| *action)
+    }
+
+    pub fn hotkey_keys(&self, hotkey: Hotkey) -> &[String]
+    {
+        self.hotkeys.iter().find(|(bound, _)| *bound == hotkey).map_or(&[], |(_, keys)| keys.as_slice())
+    }
+
+    // every (keyboard key, action) pair, hotkeys first so a key bound to both acts as the hotkey
+    pub fn bindings(&self) -> Vec<(&str, KeyAction)>
+    {
+        let hotkeys = self.hotkeys.iter()
+            .flat_map(|(hotkey, keys)| keys.iter().map(move |key| (key.as_str(), KeyAction::Hotkey(*hotkey))));
+        let keypad = self.keypad.iter().enumerate()
+            .flat_map(|(chip8_key, keys)| keys.iter().map(move |key| (key.as_str(), KeyAction::Keypad(chip8_key as u8))));
+
+        return hotkeys.chain(keypad).collect();
+    }
+
+    // keyboard keys bound to more than one action (key names aren't case sensitive), e.g.
+    // "P is bound to the pause hotkey and chip8 key 5". Only the first of those actions gets the key.
+    pub fn conflicts(&self) -> Vec<String>
+    {
+        let mut actions_by_key: BTreeMap<String, (&str, Vec<KeyAction>)> = BTreeMap::new();
+        for (key, action) in self.bindings() {
+            let (_, actions) = actions_by_key.entry(key.to_lowercase()).or_insert((key, Vec::new()));
+            if !actions.contains(&action) {
+                actions.push(action);
+            }
+        }
+
+        return actions_by_key.values()
+            .filter(|(_, actions)| actions.len() > 1)
+            .map(|(key, actions)| {
+                let names: Vec<String> = actions.iter().map(|action| action.to_string()).collect();
+                format!("{} is bound to {}", key, names.join(" and "))
+            })
+            .collect();
+    }
+}
+
+pub fn make_key_bindings() -> KeyBindings
+{
+    KeyBindings {
+        keypad: DEFAULT_KEYS.map(|key| vec![key.to_string()]),
+        hotkeys: HOTKEYS.iter().map(|hotkey| (*hotkey, hotkey.default_key().into_iter().map(str::to_string).collect())).collect(),
+        gamepad: DEFAULT_GAMEPAD.iter()
+            .map(|(button, action)| (button.to_string(), KeyAction::from_name(action).expect("invalid default controller binding")))
+            .collect()
+    }
+}
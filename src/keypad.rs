@@ -0,0 +1,50 @@
+pub const NUM_KEYS: usize = 16;
+
+// State of the 16-key hex keypad (0x0 - 0xF). Frontends translate their own
+// input events into press/release calls, the CPU only ever reads it.
+pub struct Keypad {
+    keys_pressed: [bool; NUM_KEYS]
+}
+
+impl Keypad {
+
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        if (key as usize) < NUM_KEYS {
+            self.keys_pressed[key as usize] = pressed;
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        self.keys_pressed = [false; NUM_KEYS];
+    }
+
+    pub fn is_key_pressed(&self, key: u8) -> bool {
+        (key as usize) < NUM_KEYS && self.keys_pressed[key as usize]
+    }
+
+    #[inline(always)]
+    pub fn is_any_key_pressed(&self) -> bool {
+        self.keys_pressed.iter().any(|pressed| *pressed)
+    }
+
+    // bit n set = key n held, for recording and replaying input
+    pub fn state(&self) -> u16 {
+        self.keys_pressed.iter().enumerate().fold(0, |bits, (key, pressed)| bits | ((*pressed as u16) << key))
+    }
+
+    pub fn set_state(&mut self, bits: u16) {
+        for (key, pressed) in self.keys_pressed.iter_mut().enumerate() {
+            *pressed = (bits >> key) & 1 == 1;
+        }
+    }
+
+    // the lowest numbered key held, so the same keys always resolve to the same one
+    pub fn first_key_pressed(&self) -> Option<u8> {
+        self.keys_pressed.iter().position(|pressed| *pressed).map(|key| key as u8)
+    }
+}
+
+pub fn make_keypad() -> Keypad
+{
+    Keypad { keys_pressed: [false; NUM_KEYS] }
+}
@@ -0,0 +1,23 @@
+// Emulation core: everything needed to run a ROM without a window.
+// Frontends (see renderer.rs in the binary) drive it and present its output.
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+pub mod assembler;
+pub mod audio;
+pub mod config;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod display;
+pub mod keymap;
+pub mod keypad;
+pub mod machine;
+pub mod movie;
+pub mod quirks;
+pub mod events;
+pub mod gamepad;
+pub mod rewind;
+pub mod rom_db;
+pub mod rom_loader;
+pub mod save;
+pub mod test_runner;
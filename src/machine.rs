@@ -0,0 +1,146 @@
+use crate::cpu::*;
+use crate::display::*;
+use crate::keypad::*;
+use crate::save::*;
+
+pub const DEFAULT_CLOCK_SPEED: u32 = 700; // Hz
+
+#[derive(Debug, PartialEq)]
+pub enum StepResult
+{
+    Executed,
+    FrameDone,      // the instruction completed a frame and the timers ticked
+    Error(CpuError) // nothing was executed, see CpuError
+}
+
+// One complete emulator instance: cpu (registers, ram, timers), framebuffer and keypad.
+// Instances share nothing, so several can run side by side in one process.
+pub struct Machine {
+    cpu: CPU,
+    display: Display,
+    keypad: Keypad,
+    instructions_per_frame: u32,
+    frame_cycle: u32, // instructions executed so far in the current frame
+    frame_count: u64  // frames completed since the machine was made
+}
+
+impl Machine {
+
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        self.cpu.step(&mut self.display, &self.keypad)
+    }
+
+    pub fn save_state(&mut self, save: &mut Save)
+    {
+        save.write_section(CPU_SECTION, |section| self.cpu.save_state(section));
+        save.write_section(DISPLAY_SECTION, |section| self.display.save_state(section));
+        save.write_section(RNG_SECTION, |section| self.cpu.save_rng_state(section));
+        save.write_section(KEY_WAIT_SECTION, |section| self.cpu.save_key_wait_state(section));
+    }
+
+    // loads into copies first so a bad save leaves the running machine untouched
+    pub fn load_state(&mut self, save: &mut Save) -> Result<(), String>
+    {
+        let mut cpu = self.cpu.clone();
+        let mut display = self.display.clone();
+        save.read_section(CPU_SECTION, |section| cpu.load_state(section))?;
+        save.read_section(DISPLAY_SECTION, |section| display.load_state(section))?;
+        if save.has_section(RNG_SECTION) {
+            save.read_section(RNG_SECTION, |section| cpu.load_rng_state(section))?;
+        }
+        if save.has_section(KEY_WAIT_SECTION) {
+            save.read_section(KEY_WAIT_SECTION, |section| cpu.load_key_wait_state(section))?;
+        }
+
+        self.cpu = cpu;
+        self.display = display;
+        self.frame_cycle = 0;
+        return Ok(());
+    }
+
+    // runs one 1/TIMER_CLOCK_SPEED s frame: a fixed number of instructions followed
+    // by one timer tick, so a rom + input always produces the same frames.
+    // stops early if the cpu hits an error, the frame can be continued after dealing with it
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        loop {
+            match self.step_in_frame() {
+                StepResult::Executed => {},
+                StepResult::FrameDone => return Ok(()),
+                StepResult::Error(err) => return Err(err)
+            }
+        }
+    }
+
+    // executes a single instruction as part of the current frame, ticking the timers
+    // when it completes it. Lets a debugger stop mid-frame without changing timing
+    pub fn step_in_frame(&mut self) -> StepResult {
+        if let Err(err) = self.step() {
+            return StepResult::Error(err);
+        }
+
+        self.frame_cycle += 1;
+        if self.cpu.take_vblank_wait() || self.frame_cycle >= self.instructions_per_frame {
+            self.frame_cycle = 0;
+            self.frame_count += 1;
+            self.cpu.tick_timers();
+            return StepResult::FrameDone;
+        }
+
+        return StepResult::Executed;
+    }
+
+    pub fn set_clock_speed(&mut self, clock_speed: u32)
+    {
+        self.instructions_per_frame = (clock_speed / TIMER_CLOCK_SPEED).max(1);
+    }
+
+    #[inline(always)]
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    #[inline(always)]
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    #[inline(always)]
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    #[inline(always)]
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    #[inline(always)]
+    pub fn keypad(&self) -> &Keypad {
+        &self.keypad
+    }
+
+    #[inline(always)]
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    #[inline(always)]
+    pub fn keypad_mut(&mut self) -> &mut Keypad {
+        &mut self.keypad
+    }
+}
+
+pub fn make_machine() -> Machine
+{
+    let mut machine = Machine {
+        cpu: make_cpu(),
+        display: make_display(),
+        keypad: make_keypad(),
+        instructions_per_frame: DEFAULT_CLOCK_SPEED / TIMER_CLOCK_SPEED,
+        frame_cycle: 0,
+        frame_count: 0
+    };
+
+    machine.cpu.init();
+    return machine;
+}
@@ -1,139 +1,699 @@
-mod cpu;
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+mod cli;
+#[cfg(feature = "sdl")]
 mod renderer;
-mod rom_loader;
-mod events;
-mod save;
-use cpu::*;
+use chip8::assembler::*;
+use chip8::audio::*;
+use chip8::config::*;
+use chip8::cpu::*;
+use chip8::debugger::*;
+use chip8::disassembler::*;
+use chip8::machine::*;
+use chip8::movie::*;
+use chip8::rom_loader;
+#[cfg(feature = "sdl")]
+use chip8::events::SystemEvent;
+use chip8::keymap::*;
+use chip8::rewind::*;
+use chip8::rom_db::*;
+use chip8::save::*;
+use chip8::test_runner::*;
+use cli::*;
+#[cfg(feature = "sdl")]
 use renderer::*;
-use events::SystemEvent;
-use save::*;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "sdl")]
+use std::time::{Duration, Instant};
 
-const CLOCK_SPEED: u32 = 700; // hz
+#[cfg(not(feature = "sdl"))]
+const HEADLESS_RUN_SECS: u32 = 5;
 
-fn print_args_help() {
-    println!("Run the emulator using: chip8 '<rom path>' -mode (chip8|chip48)");
-    println!("\t-mode: (Optional) emulator can run in two modes, select the one that your rom was written for");
-    println!("Load a saved state from savefile using: chip8 -load '<savefile>'");
+const RUN_COMMAND: Command = Command {
+    name: "run",
+    args: "[<rom>]",
+    about: "Run a rom.\n\
+            F1-F9 save to a slot, Shift+F1-F9 load it back, Backspace rewinds, P pauses, F11 toggles fullscreen,\n\
+            B breaks into the debugger and M prompts for a watchpoint (rebind them in the settings file).",
+    flags: &[
+        Flag { name: "config", value: Some("<file>"), help: "settings file to use instead of <config dir>/chip8/config.toml,\n\
+            the flags below override it" },
+        Flag { name: "mode", value: Some("<mode>"), help: "chip8, chip48, schip or xochip, the instruction set the rom was written for (default chip8)" },
+        Flag { name: "speed", value: Some("<hz>"), help: "instructions per second (default 700)" },
+        Flag { name: "scale", value: Some("<n>"), help: "window pixels per lores pixel at the start (default 10)" },
+        Flag { name: "scaling", value: Some("<mode>"), help: "integer (default) or aspect, how the display fills a resized window" },
+        Flag { name: "fullscreen", value: None, help: "start in fullscreen" },
+        Flag { name: "palette", value: Some("<palette>"), help: "mono, green, amber, lcd, octo or up to four colours, e.g. #000000,#33FF66\n\
+            (background, plane 0, plane 1, both planes)" },
+        Flag { name: "load-state", value: Some("<save>"), help: "start from a save state, the rom can be left out" },
+        Flag { name: "quirks", value: Some("<spec>"), help: "quirk presets and overrides on top of the mode's preset, e.g. vip,clip_sprites=off\n\
+            presets: vip, chip48, schip, xochip. flags: shift_vy, load_store_inc_i, jump_vx, vf_reset,\n\
+            clip_sprites, display_wait, fx1e_vf, key_release (only the vip preset sets key_release)" },
+        Flag { name: "volume", value: Some("<0-100>"), help: "sound volume" },
+        Flag { name: "pitch", value: Some("<hz>"), help: "square wave frequency" },
+        Flag { name: "mute", value: None, help: "no sound" },
+        Flag { name: "wav", value: Some("<path>"), help: "headless builds only, write the run's sound to a wav file" },
+        Flag { name: "debug", value: None, help: "start paused in the debugger console" },
+        Flag { name: "break", value: Some("<addr>"), help: "debugger breakpoint on pc, can be repeated" },
+        Flag { name: "break-op", value: Some("<pattern>"), help: "debugger breakpoint on an opcode pattern e.g. D..., can be repeated" },
+        Flag { name: "watch", value: Some("<range>"), help: "pause on memory access, <start>[-<end>][:rwx] e.g. 0x300-0x30F:w, can be repeated" },
+        Flag { name: "on-error", value: Some("<action>"), help: "on a cpu error halt (default), pause in the debugger or ignore the instruction" },
+        Flag { name: "save-dir", value: Some("<dir>"), help: "where save slots are kept, one folder per rom (default saves)" },
+        Flag { name: "rewind-mem", value: Some("<MiB>"), help: "rewind history size (default 16), 0 turns rewinding off" },
+        Flag { name: "rewind-interval", value: Some("<frames>"), help: "frames between rewind snapshots (default 2)" },
+        Flag { name: "seed", value: Some("<n>"), help: "seed CXNN's random numbers so runs are reproducible" },
+        Flag { name: "rng-script", value: Some("<v,v,...>"), help: "values CXNN returns first (masked as usual), e.g. 0,0x1F,7" },
+        Flag { name: "record", value: Some("<movie>"), help: "record the keypad input of the run from power-on" },
+        Flag { name: "replay", value: Some("<movie>"), help: "play a recorded movie back" }
+    ]
+};
+
+const ASM_COMMAND: Command = Command {
+    name: "asm",
+    args: "<file.8o>",
+    about: "Assemble Octo source into a rom.",
+    flags: &[
+        Flag { name: "output", value: Some("<out.ch8>"), help: "where to write the rom (default: the source name with .ch8)" }
+    ]
+};
+
+const DISASM_COMMAND: Command = Command {
+    name: "disasm",
+    args: "<rom>",
+    about: "Disassemble a rom to stdout.",
+    flags: &[
+        Flag { name: "mode", value: Some("<mode>"), help: "chip8, chip48, schip or xochip (default chip8)" },
+        Flag { name: "syntax", value: Some("<syntax>"), help: "octo or classic (default octo)" }
+    ]
+};
+
+const TEST_COMMAND: Command = Command {
+    name: "test",
+    args: "<test file or dir>...",
+    about: "Run rom tests against their golden images, see tests/golden.",
+    flags: &[
+        Flag { name: "update", value: None, help: "rewrite the golden images from the current output" }
+    ]
+};
+
+const COMMANDS: [&Command; 4] = [&RUN_COMMAND, &ASM_COMMAND, &DISASM_COMMAND, &TEST_COMMAND];
+
+fn print_usage() {
+    println!("Usage: chip8 <command> [args] [flags]");
+    println!("       chip8 <rom> [flags] (same as chip8 run <rom> [flags])");
+    println!();
+    println!("Commands:");
+    for command in COMMANDS {
+        println!("  {:8}{}", command.name, command.about.lines().next().unwrap_or(""));
+    }
+    println!();
+    println!("See 'chip8 <command> --help' for its flags.");
 }
 
-fn handle_args(args: &Vec<String>, cpu: &mut CPU, renderer: &mut Renderer) -> bool {
-    match args.len() {
-        0 | 1 => {
-            println!("No rom specified, exiting");
-            false
-        },
-        2 => {
-            let prog_path = args[1].as_str();
-            rom_loader::load_prog(cpu, &prog_path)
-        },
-        3 => {
-            let loading_savefile = args[1].as_str() == "-load";
-            let mut save = make_save();
-            loading_savefile && save.load(args[2].as_str(), cpu, renderer)
+// frontend settings that aren't part of the machine
+#[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+struct RunOptions {
+    audio: AudioSettings,
+    wav_path: Option<String>,
+    scale: u32,
+    scaling: Scaling,
+    fullscreen: bool,
+    palette: [u32; 4],
+    keys: KeyBindings,
+    debugger: Option<Debugger>,
+    save_dir: PathBuf,
+    rom_name: String, // save slots are kept per rom under save_dir
+    rewind_memory: usize, // bytes, 0 turns rewinding off
+    rewind_interval: u32, // frames between rewind snapshots
+    record_path: Option<PathBuf>,
+    movie: MovieState,
+    on_error: ErrorAction
+}
+
+// the movie being recorded or played back
+struct MovieState
+{
+    recording: Option<Movie>,
+    replay: Option<Movie>,
+    replay_frame: usize,
+    input_frame: Option<u64> // Machine::frame_count of the frame movie_frame last took the input for
+}
+
+impl MovieState {
+    #[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+    fn is_active(&self) -> bool {
+        self.recording.is_some() || self.replay.is_some()
+    }
+}
+
+// what to do when the cpu can't execute an instruction, 00FD always ends the run
+#[derive(Clone, Copy, PartialEq)]
+enum ErrorAction
+{
+    Halt,
+    Pause, // break into the debugger at the instruction
+    Ignore // skip the instruction and carry on
+}
+
+// parses the value of a flag that was given, 'expected' describes valid values for the error
+fn flag_value<T>(args: &ParsedArgs, name: &str, expected: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, String> {
+    match args.value(name) {
+        Some(value) => parse(value).map(Some).ok_or(format!("invalid --{} '{}', expected {}", name, value, expected)),
+        None => Ok(None)
+    }
+}
+
+// 'chip8 run [<rom>] [flags]', sets up the machine and frontend options then runs until the window closes
+fn run_command(args: &ParsedArgs) -> Result<(), String> {
+    let rom_path = match args.positionals.as_slice() {
+        [] => None,
+        [rom] => Some(Path::new(rom)),
+        [_, extra, ..] => return Err(format!("unexpected argument '{}', run takes a single rom", extra))
+    };
+    let state_path = args.value("load-state").map(Path::new);
+    if rom_path.is_none() && state_path.is_none() {
+        return Err("no rom given, pass a rom or --load-state <save>".to_string());
+    }
+
+    println!();
+    println!();
+
+    // defaults < config file < flags
+    let config = match args.value("config").map(PathBuf::from).or_else(default_config_path) {
+        Some(path) => load_config(&path)?,
+        None => make_config()
+    };
+
+    let mut machine: Machine = make_machine();
+    let mut options = RunOptions {
+        audio: DEFAULT_AUDIO_SETTINGS,
+        wav_path: args.value("wav").map(str::to_string),
+        scale: flag_value(args, "scale", &format!("1 - {}", MAX_SCALE), |text| text.parse().ok().filter(|scale| (1..=MAX_SCALE).contains(scale)))?.unwrap_or(config.scale),
+        scaling: flag_value(args, "scaling", "integer or aspect", parse_scaling)?.unwrap_or(config.scaling),
+        fullscreen: config.fullscreen || args.has("fullscreen"),
+        palette: config.palette,
+        keys: config.keys.clone(),
+        debugger: None,
+        save_dir: args.value("save-dir").map(PathBuf::from).unwrap_or(config.save_dir.clone()),
+        rom_name: "unknown".to_string(),
+        rewind_memory: flag_value(args, "rewind-mem", "a size in MiB", |text| text.parse::<usize>().ok().and_then(|mib| mib.checked_mul(1024 * 1024)))?.unwrap_or(DEFAULT_REWIND_MEMORY),
+        rewind_interval: flag_value(args, "rewind-interval", "a number of frames", |text| text.parse().ok().filter(|frames| *frames > 0))?.unwrap_or(DEFAULT_REWIND_INTERVAL),
+        record_path: args.value("record").map(PathBuf::from),
+        movie: MovieState { recording: None, replay: None, replay_frame: 0, input_frame: None },
+        on_error: flag_value(args, "on-error", "halt, pause or ignore", |text| match text {
+            "halt" => Some(ErrorAction::Halt),
+            "pause" => Some(ErrorAction::Pause),
+            "ignore" => Some(ErrorAction::Ignore),
+            _ => None
+        })?.unwrap_or(ErrorAction::Halt)
+    };
+
+    if let Some(volume) = flag_value(args, "volume", "0 - 100", |text| text.parse::<f32>().ok().filter(|volume| (0.0..=100.0).contains(volume)))? {
+        options.audio.volume = volume / 100.0;
+    }
+    if let Some(pitch) = flag_value(args, "pitch", "a frequency in Hz", |text| text.parse::<f32>().ok().filter(|pitch| *pitch > 0.0))? {
+        options.audio.pitch = pitch;
+    }
+    options.audio.muted = args.has("mute");
+
+    if let Some(path) = args.value("replay") {
+        options.movie.replay = Some(load_movie(Path::new(path)).map_err(|err| format!("invalid --replay: {}", err))?);
+    }
+
+    // movies start from power-on, not from a save
+    if state_path.is_some() && (options.record_path.is_some() || options.movie.replay.is_some()) {
+        return Err("--record and --replay start from power-on, they can't be combined with --load-state".to_string());
+    }
+
+    if args.has("debug") {
+        attach_debugger(&mut options).pause();
+    }
+    for value in args.values("break") {
+        let addr = parse_addr(value).map_err(|err| format!("invalid --break: {}", err))?;
+        attach_debugger(&mut options).add_breakpoint(addr);
+    }
+    for value in args.values("break-op") {
+        let pattern = parse_opcode_pattern(value).map_err(|err| format!("invalid --break-op: {}", err))?;
+        attach_debugger(&mut options).add_opcode_breakpoint(pattern);
+    }
+    for value in args.values("watch") {
+        let watchpoint = parse_watchpoint(value).map_err(|err| format!("invalid --watch: {}", err))?;
+        attach_debugger(&mut options).add_watchpoint(watchpoint);
+    }
+
+    // mode goes first, it decides how much memory the rom may use. The rom database picks it (and the
+    // rest of the rom's settings) for known roms, flags still win over it
+    let forced_mode = flag_value(args, "mode", "chip8, chip48, schip or xochip", parse_mode)?;
+    machine.cpu_mut().set_mode(forced_mode.unwrap_or(config.mode));
+    let speed = flag_value(args, "speed", "instructions per second, at least 60", |text| text.parse::<u32>().ok().filter(|speed| *speed >= TIMER_CLOCK_SPEED))?;
+
+    // loading the rom first lets the save's rom hash be checked against it
+    let mut rom_settings = None;
+    if let Some(path) = rom_path {
+        let database = load_rom_database(user_rom_database_path().as_deref())?;
+        rom_settings = rom_loader::load_prog(machine.cpu_mut(), path, &database, forced_mode)?;
+    }
+    machine.set_clock_speed(speed.or(rom_settings.as_ref().and_then(|settings| settings.speed)).unwrap_or(config.speed));
+    if let Some(settings) = &rom_settings {
+        for (slot, color) in options.palette.iter_mut().zip(settings.palette.iter()) {
+            *slot = *color;
         }
-        4 => {
-            let prog_path = args[1].as_str();
-            let rom_loaded = rom_loader::load_prog(cpu, &prog_path);
-            if !rom_loaded {
-                println!("Error loading rom, path may be wrong");
-            }
+    }
+    if let Some(palette) = args.value("palette") {
+        let items: Vec<&str> = palette.split(',').map(str::trim).collect();
+        options.palette = parse_palette(&items, options.palette).map_err(|err| format!("invalid --palette: {}", err))?;
+    }
+    if let Some(path) = state_path {
+        let mut save = make_save();
+        save.load(path, &mut machine).map_err(|err| format!("failed to load state: {}", err))?;
+    }
+
+    // slots go under the rom's file name, a save started from without a rom keeps using the slot directory it came from
+    let name_source = match (rom_path, state_path) {
+        (Some(rom), _) => rom.file_stem(),
+        (None, Some(save)) => save.parent().and_then(|dir| dir.file_name()),
+        (None, None) => None
+    };
+    if let Some(name) = name_source.filter(|name| !name.is_empty()) {
+        options.rom_name = name.to_string_lossy().to_string();
+    }
 
-            let mode_specified = args[2].as_str() == "-mode";
-
-            rom_loaded && mode_specified && match args[3].as_str() {
-                "chip8" => {
-                    cpu.set_mode(CPUMode::Chip8);
-                    true
-                },
-                "chip48" => {
-                    cpu.set_mode(CPUMode::Chip48);
-                    true
-                },
-                _ => false
+    // the rom's own bindings from the settings file, if it has any
+    options.keys = config.key_bindings(&options.rom_name).clone();
+    for conflict in options.keys.conflicts() {
+        println!("Key binding conflict: {}", conflict);
+    }
+    if let Some(settings) = &rom_settings {
+        print_key_hints(settings, &options.keys);
+    }
+
+    let mut quirks = machine.cpu().quirks();
+    config.apply_quirks(&mut quirks);
+    if let Some(spec) = args.value("quirks") {
+        quirks.apply_overrides(spec).map_err(|err| format!("invalid --quirks: {}", err))?;
+    }
+    machine.cpu_mut().set_quirks(quirks);
+
+    if let Some(seed) = flag_value(args, "seed", "a number", |text| parse_number(text).and_then(|seed| u64::try_from(seed).ok()))? {
+        machine.cpu_mut().seed_rng(seed);
+    }
+    let rng_script = flag_value(args, "rng-script", "comma separated values 0 - 255", |text| {
+        text.split(',').map(|item| parse_number(item).and_then(|n| u8::try_from(n).ok())).collect::<Option<Vec<u8>>>()
+    })?;
+    machine.cpu_mut().set_rng_script(&rng_script.unwrap_or_default());
+
+    // after --quirks, --seed and --rng-script so the recording captures them, a replay brings its own
+    if let Some(replay) = &options.movie.replay {
+        replay.start_playback(&mut machine).map_err(|err| format!("invalid --replay: {}", err))?;
+        println!("Replaying {} frames", replay.len());
+    } else if options.record_path.is_some() {
+        options.movie.recording = Some(start_recording(&machine));
+    }
+
+    run(&mut machine, &mut options);
+    return Ok(());
+}
+
+// e.g. "Keys: left = 5 (W), right = 6 (E/Right)", the rom's keys with the keyboard keys they're bound to
+fn print_key_hints(settings: &RomSettings, keys: &KeyBindings) {
+    if settings.key_hints.is_empty() {
+        return;
+    }
+
+    let hints: Vec<String> = settings.key_hints.iter()
+        .map(|(action, key)| format!("{} = {:X} ({})", action, key, keys.keypad[*key as usize].join("/")))
+        .collect();
+    println!("Keys: {}", hints.join(", "));
+}
+
+// 'chip8 disasm <rom> [--mode m] [--syntax octo|classic]', prints the listing to stdout
+fn disasm_command(args: &ParsedArgs) -> Result<(), String> {
+    let [rom_path] = args.positionals.as_slice() else {
+        return Err("disasm takes one rom".to_string());
+    };
+
+    let mode = flag_value(args, "mode", "chip8, chip48, schip or xochip", parse_mode)?.unwrap_or(CPUMode::Chip8);
+    let syntax = flag_value(args, "syntax", "octo or classic", |text| match text {
+        "octo" => Some(Syntax::Octo),
+        "classic" => Some(Syntax::Classic),
+        _ => None
+    })?.unwrap_or(Syntax::Octo);
+
+    let rom = std::fs::read(rom_path).map_err(|err| format!("failed to read {}: {}", rom_path, err))?;
+    print!("{}", format_listing(&disassemble(&rom, mode, syntax), syntax));
+    return Ok(());
+}
+
+// 'chip8 test <test file or dir>... [--update]', runs rom tests against their golden images
+fn test_command(args: &ParsedArgs) -> Result<(), String> {
+    let update = args.has("update");
+    if args.positionals.is_empty() {
+        return Err("no tests given, pass test files or directories of them".to_string());
+    }
+
+    // directories hold one .txt test per file
+    let mut test_paths: Vec<PathBuf> = Vec::new();
+    for target in args.positionals.iter() {
+        let path = PathBuf::from(target);
+        if path.is_dir() {
+            let entries = std::fs::read_dir(&path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+            let mut dir_tests: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|entry| entry.extension().is_some_and(|ext| ext == "txt"))
+                .collect();
+            dir_tests.sort();
+            test_paths.extend(dir_tests);
+        } else {
+            test_paths.push(path);
+        }
+    }
+
+    let mut failed = 0;
+    for path in test_paths.iter() {
+        let result = load_rom_test(path).and_then(|test| if update { test.update_golden() } else { test.check() });
+        match result {
+            Ok(()) => println!("{} ... {}", path.display(), if update { "updated" } else { "ok" }),
+            Err(err) => {
+                println!("{} ... FAILED\n{}", path.display(), err);
+                failed += 1;
             }
+        }
+    }
+
+    println!("{} tests, {} failed", test_paths.len(), failed);
+    if failed > 0 {
+        return Err(format!("{} of {} tests failed", failed, test_paths.len()));
+    }
+
+    return Ok(());
+}
+
+// 'chip8 asm <file.8o> [--output out.ch8]', writes the assembled rom
+fn asm_command(args: &ParsedArgs) -> Result<(), String> {
+    let [source_path] = args.positionals.as_slice() else {
+        return Err("asm takes one source file".to_string());
+    };
+
+    let out_path = match args.value("output") {
+        Some(out) => PathBuf::from(out),
+        None => Path::new(source_path).with_extension("ch8")
+    };
+
+    let source = std::fs::read_to_string(source_path).map_err(|err| format!("failed to read {}: {}", source_path, err))?;
+    let rom = assemble(&source).map_err(|err| format!("{}:{}", source_path, err))?;
+    std::fs::write(&out_path, &rom).map_err(|err| format!("failed to write {}: {}", out_path.display(), err))?;
+    println!("Wrote {} bytes to {}", rom.len(), out_path.display());
+    return Ok(());
+}
+
+// decimal or 0x prefixed hex
+fn parse_number(text: &str) -> Option<u128> {
+    match text.trim().strip_prefix("0x") {
+        Some(hex) => u128::from_str_radix(hex, 16).ok(),
+        None => text.trim().parse::<u128>().ok()
+    }
+}
+
+// sleeps off whatever is left of the current frame
+#[cfg(feature = "sdl")]
+fn wait_for_next_frame(frame_start: Instant) {
+    let frame_dur = Duration::from_secs_f32(1.0 / TIMER_CLOCK_SPEED as f32);
+    std::thread::sleep(frame_dur.saturating_sub(frame_start.elapsed()));
+}
+
+// the debugger, created on first use. It only breaks on cpu errors when --on-error says to pause
+fn attach_debugger(options: &mut RunOptions) -> &mut Debugger {
+    let break_on_invalid = options.on_error == ErrorAction::Pause;
+    options.debugger.get_or_insert_with(|| {
+        let mut debugger = make_debugger();
+        debugger.set_break_on_invalid(break_on_invalid);
+        debugger
+    })
+}
+
+// runs a frame, through the debugger if there is one so breakpoints get checked,
+// and deals with a cpu error as --on-error says. returns false once the program has halted
+fn run_frame(machine: &mut Machine, options: &mut RunOptions) -> bool {
+    let result = match options.debugger.as_mut() {
+        Some(debugger) => debugger.run_frame(machine),
+        None => machine.run_frame()
+    };
+
+    let Err(err) = result else {
+        return true;
+    };
+
+    if let CpuError::Exit { .. } = err {
+        println!("Program exited");
+        return false;
+    }
+
+    println!("CPU error: {}", err);
+    match options.on_error {
+        ErrorAction::Halt => return false,
+        ErrorAction::Pause => {
+            attach_debugger(options).pause();
+            print!("{}", format_cpu_state(machine.cpu()));
         },
-        _ => false
+        ErrorAction::Ignore => machine.cpu_mut().skip_instruction()
     }
+
+    return true;
 }
 
-fn sys_sleep(dur: f32) {
-    ::std::thread::sleep(Duration::from_secs_f32(dur));
+// input for the frame about to run: a replay overrides the keypad, a recording captures it.
+// Call before every instruction that can start a frame; in a frame the debugger stopped in the
+// middle of, the frame's input is put back so keys pressed during the pause don't count
+fn movie_frame(machine: &mut Machine, movie: &mut MovieState) {
+    if movie.input_frame == Some(machine.frame_count()) {
+        let keys = match (&movie.replay, &movie.recording) {
+            (Some(replay), _) => movie.replay_frame.checked_sub(1).and_then(|frame| replay.frame_input(frame)),
+            (None, Some(recording)) => recording.len().checked_sub(1).and_then(|frame| recording.frame_input(frame)),
+            (None, None) => None
+        };
+        if let Some(keys) = keys {
+            machine.keypad_mut().set_state(keys);
+        }
+        return;
+    }
+    movie.input_frame = Some(machine.frame_count());
+
+    if let Some(replay) = &movie.replay {
+        match replay.frame_input(movie.replay_frame) {
+            Some(keys) => {
+                machine.keypad_mut().set_state(keys);
+                movie.replay_frame += 1;
+            },
+            None => {
+                println!("Replay finished after {} frames", replay.len());
+                machine.keypad_mut().release_all();
+                movie.replay = None;
+            }
+        }
+    }
+
+    if let Some(recording) = movie.recording.as_mut() {
+        recording.record_frame(machine.keypad());
+    }
+}
+
+fn finish_recording(options: &mut RunOptions) {
+    let (Some(movie), Some(path)) = (options.movie.recording.take(), options.record_path.as_ref()) else {
+        return;
+    };
+
+    match movie.write_to_disk(path) {
+        Ok(()) => println!("Recorded {} frames to {}", movie.len(), path.display()),
+        Err(err) => println!("Error saving movie: {}", err)
+    }
 }
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
-    let mut cpu: CPU = make_cpu();
-    let mut renderer: Renderer = make_renderer();
+    let Some(first) = args.first() else {
+        print_usage();
+        return;
+    };
 
-    cpu.init();
-    renderer.init();
+    if first == "--help" || first == "-h" || first == "help" {
+        // 'chip8 help <command>' is the same as 'chip8 <command> --help'
+        match args.get(1).and_then(|name| COMMANDS.iter().find(|command| command.name == name)) {
+            Some(command) => print!("{}", command_help(command)),
+            None => print_usage()
+        }
+        return;
+    }
 
-    println!();
-    println!();
-    if !handle_args(&args, &mut cpu, &mut renderer) {
-        print_args_help();
+    // anything that isn't a command is taken as a rom (or flags) to run
+    let (command, command_args) = match COMMANDS.iter().find(|command| command.name == first) {
+        Some(command) => (*command, &args[1..]),
+        None => (&RUN_COMMAND, &args[..])
+    };
+
+    let parsed = match parse_args(command, command_args) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            println!("Error: {}", err);
+            println!("See 'chip8 {} --help'", command.name);
+            std::process::exit(2);
+        }
+    };
+
+    if parsed.help {
+        print!("{}", command_help(command));
         return;
     }
 
-    let sleep_dur = 1.0 / CLOCK_SPEED as f32;
-    let sleep_dur_ms: f32 = 1000.0 / CLOCK_SPEED as f32;
-    let mut errored = false;
+    let result = match command.name {
+        "run" => run_command(&parsed),
+        "asm" => asm_command(&parsed),
+        "disasm" => disasm_command(&parsed),
+        _ => test_command(&parsed)
+    };
 
-    let timer_thread_chan = cpu::start_timer_thread();
+    if let Err(err) = result {
+        println!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
 
-    let mut last_display_referesh_t: f32 = 0.0;
-    let display_referesh_t: f32 = 1000.0 / DISPLAY_REFRESH_RATE;
+#[cfg(feature = "sdl")]
+fn run(machine: &mut Machine, options: &mut RunOptions) {
+    if options.wav_path.is_some() {
+        println!("--wav only works in headless builds, ignoring it");
+    }
 
+    let mut renderer: Renderer = make_renderer();
+    if let Err(err) = renderer.init(options.scale, options.scaling, options.fullscreen, options.palette, &options.keys) {
+        println!("Error: {}", err);
+        return;
+    }
+
+    let mut audio = make_audio(options.audio);
+    let mut samples: Vec<f32> = Vec::with_capacity(SAMPLES_PER_FRAME);
+    let mut rewind = if options.rewind_memory > 0 { Some(make_rewind(options.rewind_memory, options.rewind_interval)) } else { None };
+
+    let mut errored = false;
     let mut paused = false;
     loop {
-        let sys_event = renderer.poll_input();
+        let frame_start = Instant::now();
+        let sys_event = renderer.poll_input(machine.keypad_mut());
 
         match sys_event {
-            SystemEvent::Exit => {
-                timer_thread_chan.send(SystemEvent::Exit).unwrap();
-                break;
+            SystemEvent::Exit => break,
+            SystemEvent::Debug => attach_debugger(options).pause(),
+            SystemEvent::Watch => attach_debugger(options).prompt_watchpoint(),
+            SystemEvent::Pause => paused = !paused,
+            SystemEvent::ToggleFullscreen => renderer.toggle_fullscreen(),
+            SystemEvent::SaveSlot(slot) => {
+                let path = slot_path(&options.save_dir, &options.rom_name, slot);
+                let mut save = make_save();
+                save.build(machine);
+                match save.write_to_disk(&path) {
+                    Ok(()) => {
+                        println!("Saved slot {} to {}", slot, path.display());
+                        renderer.show_message(&format!("Saved slot {}", slot));
+                    },
+                    Err(err) => {
+                        println!("Error saving slot {}: {}", slot, err);
+                        renderer.show_message(&format!("Save {} failed", slot));
+                    }
+                }
             },
-            SystemEvent::Pause => {
-                paused = !paused;
-                if paused {
-                    timer_thread_chan.send(SystemEvent::Pause).unwrap();
+            SystemEvent::LoadSlot(slot) => {
+                let path = slot_path(&options.save_dir, &options.rom_name, slot);
+                if options.movie.is_active() {
+                    renderer.show_message("No loading in a movie");
+                } else if !path.exists() {
+                    renderer.show_message(&format!("Slot {} is empty", slot));
                 } else {
-                    timer_thread_chan.send(SystemEvent::Resume).unwrap();
+                    let mut save = make_save();
+                    match save.load(&path, machine) {
+                        Ok(()) => {
+                            errored = false;
+                            println!("Loaded slot {} from {}", slot, path.display());
+                            renderer.show_message(&format!("Loaded slot {}", slot));
+                        },
+                        Err(err) => {
+                            println!("Error loading slot {}: {}", slot, err);
+                            renderer.show_message(&format!("Load {} failed", slot));
+                        }
+                    }
                 }
             },
-            SystemEvent::Save => {
-                let mut save = make_save();
-                
-                println!("Building save");
-                save.build(&mut cpu, &mut renderer);
+            _ => {}
+        }
 
-                println!("Writing save to disk");
-                save.write_to_disk();
+        if let Some(debugger) = options.debugger.as_mut() {
+            if debugger.is_paused() && debugger.run_console(machine, &mut |machine| movie_frame(machine, &mut options.movie)) == DebuggerAction::Quit {
+                break;
             }
-            _ => {}
         }
 
-        if paused {
-            sys_sleep(sleep_dur);
-            continue;
+        // rewinding plays back one snapshot per frame instead of emulating
+        // (not while recording or replaying a movie, it would no longer match its input)
+        let rewinding = !paused && !options.movie.is_active() && rewind.is_some() && renderer.is_rewind_held();
+        if rewinding {
+            if rewind.as_mut().unwrap().step_back(machine) {
+                errored = false;
+                renderer.show_message("Rewind");
+            } else {
+                renderer.show_message("Rewind: no history");
+            }
+        } else if !paused && !errored {
+            movie_frame(machine, &mut options.movie);
+            errored = !run_frame(machine, options);
+
+            if let Some(rewind) = rewind.as_mut() {
+                rewind.record_frame(machine);
+            }
+
+            samples.clear();
+            audio.generate_frame(machine.cpu(), &mut samples);
+            renderer.queue_audio(&samples);
         }
 
-        if !errored {
-            errored = !cpu.step(&mut renderer) || errored;
-            if errored {
-                println!("CPU error or end of code");
+        renderer.step(machine.display());
+        wait_for_next_frame(frame_start);
+    }
+
+    finish_recording(options);
+}
+
+// No window to poll for input or a quit request, so run the rom for a fixed
+// number of frames (as fast as possible, timing is frame based) and dump the
+// final frame to stdout (and the sound to a wav file if asked for)
+#[cfg(not(feature = "sdl"))]
+fn run(machine: &mut Machine, options: &mut RunOptions) {
+    let mut audio = make_audio(options.audio);
+    let mut samples: Vec<f32> = Vec::new();
+
+    // a replay runs for as long as the movie
+    let frames = options.movie.replay.as_ref().map(|replay| replay.len() as u32).unwrap_or(TIMER_CLOCK_SPEED * HEADLESS_RUN_SECS);
+    for _ in 0..frames {
+        if let Some(debugger) = options.debugger.as_mut() {
+            if debugger.is_paused() && debugger.run_console(machine, &mut |machine| movie_frame(machine, &mut options.movie)) == DebuggerAction::Quit {
+                break;
             }
         }
 
-        if last_display_referesh_t >= display_referesh_t {
-            renderer.step();
-            last_display_referesh_t = 0.0;
+        movie_frame(machine, &mut options.movie);
+        if !run_frame(machine, options) {
+            break;
         }
 
-        sys_sleep(sleep_dur);
-        last_display_referesh_t += sleep_dur_ms;
+        if options.wav_path.is_some() {
+            audio.generate_frame(machine.cpu(), &mut samples);
+        }
+    }
+
+    finish_recording(options);
+    print!("{}", machine.display().to_text_art());
+
+    if let Some(wav_path) = &options.wav_path {
+        match std::fs::write(wav_path, encode_wav(&samples, SAMPLE_RATE)) {
+            Ok(()) => println!("Wrote {} frames of sound to {}", samples.len() / SAMPLES_PER_FRAME, wav_path),
+            Err(err) => println!("Failed to write {}: {}", wav_path, err)
+        }
     }
-    
 }
@@ -0,0 +1,163 @@
+use std::fs;
+use std::path::Path;
+use crate::cpu::*;
+use crate::keypad::*;
+use crate::machine::*;
+use crate::quirks::*;
+use crate::save::crc32;
+
+// Movie file layout (integers little endian):
+//   magic "C8MV", format version u16, cpu mode u8, quirks u8, has rom hash u8, rom hash u32,
+//   instructions per frame u32, rng state u64 at power-on, rng script length u16, rng script u8 each,
+//   frame count u32, keypad state u16 per frame (bit n = key n)
+//   crc32 u32 of everything before it
+// A movie always starts from power-on with the rom freshly loaded, emulation is frame based and the
+// only other input is CXNN's rng (and --rng-script), so replaying it reproduces every frame exactly.
+
+const MOVIE_MAGIC: [u8; 4] = *b"C8MV";
+const MOVIE_FORMAT_VERSION: u16 = 2;
+const MOVIE_HEADER_SIZE: usize = 31; // without the rng script
+
+pub struct Movie {
+    mode: CPUMode,
+    quirks: Quirks,
+    rom_hash: Option<u32>,
+    instructions_per_frame: u32,
+    rng_state: u64,
+    rng_script: Vec<u8>,
+    frames: Vec<u16>
+}
+
+impl Movie {
+
+    // keypad state the frame about to run sees
+    pub fn record_frame(&mut self, keypad: &Keypad)
+    {
+        self.frames.push(keypad.state());
+    }
+
+    #[inline(always)]
+    pub fn frame_input(&self, frame: usize) -> Option<u16> {
+        self.frames.get(frame).copied()
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // puts a freshly loaded machine into the state the recording started from
+    pub fn start_playback(&self, machine: &mut Machine) -> Result<(), String>
+    {
+        if let (Some(loaded), Some(recorded)) = (machine.cpu().rom_hash(), self.rom_hash) {
+            if loaded != recorded {
+                return Err(format!("movie is for a different rom (rom crc32 {:08x}, movie was recorded with {:08x})", loaded, recorded));
+            }
+        }
+
+        machine.cpu_mut().set_mode(self.mode);
+        machine.cpu_mut().set_quirks(self.quirks);
+        machine.cpu_mut().set_rng_state(self.rng_state);
+        machine.cpu_mut().set_rng_script(&self.rng_script);
+        machine.set_clock_speed(self.instructions_per_frame * TIMER_CLOCK_SPEED);
+        return Ok(());
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let mut data: Vec<u8> = Vec::with_capacity(MOVIE_HEADER_SIZE + self.rng_script.len() + self.frames.len() * 2 + 4);
+        data.extend_from_slice(&MOVIE_MAGIC);
+        data.extend_from_slice(&MOVIE_FORMAT_VERSION.to_le_bytes());
+        data.push(mode_to_byte(self.mode));
+        data.push(self.quirks.to_bits());
+        data.push(self.rom_hash.is_some() as u8);
+        data.extend_from_slice(&self.rom_hash.unwrap_or(0).to_le_bytes());
+        data.extend_from_slice(&self.instructions_per_frame.to_le_bytes());
+        data.extend_from_slice(&self.rng_state.to_le_bytes());
+        data.extend_from_slice(&(self.rng_script.len() as u16).to_le_bytes());
+        data.extend_from_slice(&self.rng_script);
+        data.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        for keys in self.frames.iter() {
+            data.extend_from_slice(&keys.to_le_bytes());
+        }
+
+        let crc = crc32(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        return data;
+    }
+
+    pub fn write_to_disk(&self, path: &Path) -> Result<(), String>
+    {
+        fs::write(path, self.to_bytes()).map_err(|err| format!("failed to write {}: {}", path.display(), err))
+    }
+}
+
+// captures the settings and rng state a replay needs, call before the first frame
+pub fn start_recording(machine: &Machine) -> Movie
+{
+    Movie {
+        mode: machine.cpu().mode(),
+        quirks: machine.cpu().quirks(),
+        rom_hash: machine.cpu().rom_hash(),
+        instructions_per_frame: machine.instructions_per_frame(),
+        rng_state: machine.cpu().rng_state(),
+        rng_script: machine.cpu().rng_script(),
+        frames: Vec::new()
+    }
+}
+
+pub fn parse_movie(data: &[u8]) -> Result<Movie, String>
+{
+    if data.len() < MOVIE_HEADER_SIZE + 4 || data[..4] != MOVIE_MAGIC {
+        return Err("not a chip8 movie file".to_string());
+    }
+
+    let crc_ptr = data.len() - 4;
+    if crc32(&data[..crc_ptr]) != u32::from_le_bytes(data[crc_ptr..].try_into().unwrap()) {
+        return Err("movie file is corrupt (checksum mismatch)".to_string());
+    }
+
+    let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    let version = u16::from_le_bytes([data[4], data[5]]);
+    if version != MOVIE_FORMAT_VERSION {
+        return Err(format!("movie format version {} isn't supported, this build reads version {}", version, MOVIE_FORMAT_VERSION));
+    }
+
+    let mode = mode_from_byte(data[6]).ok_or(format!("invalid cpu mode {} in movie", data[6]))?;
+    let script_len = u16::from_le_bytes([data[25], data[26]]) as usize;
+    let frames_ptr = MOVIE_HEADER_SIZE + script_len;
+    if frames_ptr > crc_ptr {
+        return Err(format!("movie says it has {} rng script values but is too short to hold them", script_len));
+    }
+
+    let frame_count = u32_at(frames_ptr - 4) as usize;
+    if crc_ptr - frames_ptr != frame_count * 2 {
+        return Err(format!("movie says it has {} frames but holds {}", frame_count, (crc_ptr - frames_ptr) / 2));
+    }
+
+    let frames = data[frames_ptr..crc_ptr]
+        .chunks_exact(2)
+        .map(|keys| u16::from_le_bytes([keys[0], keys[1]]))
+        .collect();
+
+    Ok(Movie {
+        mode,
+        quirks: Quirks::from_bits(data[7]),
+        rom_hash: if data[8] != 0 { Some(u32_at(9)) } else { None },
+        instructions_per_frame: u32_at(13).max(1),
+        rng_state: u64::from_le_bytes(data[17..25].try_into().unwrap()),
+        rng_script: data[27..27 + script_len].to_vec(),
+        frames
+    })
+}
+
+pub fn load_movie(path: &Path) -> Result<Movie, String>
+{
+    let data = fs::read(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    return parse_movie(&data);
+}
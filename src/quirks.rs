@@ -0,0 +1,145 @@
+use crate::cpu::CPUMode;
+
+// Behaviour differences between interpreters, one flag per quirk.
+// Every CPUMode starts from its preset, individual flags can be overridden after.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+    pub shift_vy: bool,         // 8XY6/8XYE shift VY into VX (VIP) instead of shifting VX in place
+    pub load_store_inc_i: bool, // FX55/FX65 leave I pointing past the last register
+    pub jump_vx: bool,          // BXNN jumps to XNN + VX instead of BNNN to NNN + V0
+    pub vf_reset: bool,         // 8XY1/8XY2/8XY3 reset VF to 0
+    pub clip_sprites: bool,     // sprites are clipped at the screen edges instead of wrapping around
+    pub display_wait: bool,     // DXYN waits for the next frame (vblank) before executing further
+    pub fx1e_vf: bool,          // FX1E sets VF to 1 when I goes past addressable memory, 0 otherwise
+    pub key_release: bool,      // FX0A finishes when the key is released (VIP) instead of as soon as it's pressed
+}
+
+// flag names for --quirks and the settings file's [quirks] table, in declaration order
+pub const QUIRK_NAMES: [&str; 8] = [
+    "shift_vy", "load_store_inc_i", "jump_vx", "vf_reset", "clip_sprites", "display_wait", "fx1e_vf", "key_release"
+];
+
+pub const VIP_QUIRKS: Quirks = Quirks {
+    shift_vy: true,
+    load_store_inc_i: true,
+    jump_vx: false,
+    vf_reset: true,
+    clip_sprites: true,
+    display_wait: true,
+    fx1e_vf: false,
+    key_release: true
+};
+
+pub const CHIP48_QUIRKS: Quirks = Quirks {
+    shift_vy: false,
+    load_store_inc_i: false,
+    jump_vx: true,
+    vf_reset: false,
+    clip_sprites: true,
+    display_wait: false,
+    fx1e_vf: false,
+    key_release: false
+};
+
+pub const SCHIP_QUIRKS: Quirks = CHIP48_QUIRKS;
+
+pub const XOCHIP_QUIRKS: Quirks = Quirks {
+    shift_vy: true,
+    load_store_inc_i: true,
+    jump_vx: false,
+    vf_reset: false,
+    clip_sprites: false,
+    display_wait: false,
+    fx1e_vf: false,
+    key_release: false
+};
+
+pub fn default_quirks(mode: &CPUMode) -> Quirks
+{
+    match mode {
+        CPUMode::Chip8 => VIP_QUIRKS,
+        CPUMode::Chip48 => CHIP48_QUIRKS,
+        CPUMode::SuperChip => SCHIP_QUIRKS,
+        CPUMode::XoChip => XOCHIP_QUIRKS
+    }
+}
+
+impl Quirks {
+
+    // Applies a comma separated list of presets and flag overrides, in order,
+    // e.g. "schip,shift_vy=on,clip_sprites=off". Flags accept on/off, true/false or 1/0
+    pub fn apply_overrides(&mut self, spec: &str) -> Result<(), String>
+    {
+        for item in spec.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+            let Some((name, value)) = item.split_once('=') else {
+                *self = match item {
+                    "vip" | "chip8" => VIP_QUIRKS,
+                    "chip48" => CHIP48_QUIRKS,
+                    "schip" => SCHIP_QUIRKS,
+                    "xochip" => XOCHIP_QUIRKS,
+                    _ => return Err(format!("unknown quirk preset '{}', expected vip, chip48, schip or xochip", item))
+                };
+                continue;
+            };
+
+            let enabled = match value.trim() {
+                "on" | "true" | "1" => true,
+                "off" | "false" | "0" => false,
+                _ => return Err(format!("invalid value '{}' for quirk '{}', expected on or off", value, name))
+            };
+
+            *self.flag_mut(name.trim())? = enabled;
+        }
+
+        return Ok(());
+    }
+
+    pub fn set_flag(&mut self, name: &str, enabled: bool) -> Result<(), String>
+    {
+        *self.flag_mut(name)? = enabled;
+        return Ok(());
+    }
+
+    fn flag_mut(&mut self, name: &str) -> Result<&mut bool, String>
+    {
+        match name {
+            "shift_vy" => Ok(&mut self.shift_vy),
+            "load_store_inc_i" => Ok(&mut self.load_store_inc_i),
+            "jump_vx" => Ok(&mut self.jump_vx),
+            "vf_reset" => Ok(&mut self.vf_reset),
+            "clip_sprites" => Ok(&mut self.clip_sprites),
+            "display_wait" => Ok(&mut self.display_wait),
+            "fx1e_vf" => Ok(&mut self.fx1e_vf),
+            "key_release" => Ok(&mut self.key_release),
+            _ => Err(format!("unknown quirk '{}'", name))
+        }
+    }
+
+    // one bit per flag, in declaration order. Saves and movies made before key_release existed read it
+    // as off, which is how FX0A behaved back then
+    pub fn to_bits(&self) -> u8
+    {
+        (self.shift_vy as u8)
+            | (self.load_store_inc_i as u8) << 1
+            | (self.jump_vx as u8) << 2
+            | (self.vf_reset as u8) << 3
+            | (self.clip_sprites as u8) << 4
+            | (self.display_wait as u8) << 5
+            | (self.fx1e_vf as u8) << 6
+            | (self.key_release as u8) << 7
+    }
+
+    pub fn from_bits(bits: u8) -> Quirks
+    {
+        Quirks {
+            shift_vy: bits & 1 > 0,
+            load_store_inc_i: (bits >> 1) & 1 > 0,
+            jump_vx: (bits >> 2) & 1 > 0,
+            vf_reset: (bits >> 3) & 1 > 0,
+            clip_sprites: (bits >> 4) & 1 > 0,
+            display_wait: (bits >> 5) & 1 > 0,
+            fx1e_vf: (bits >> 6) & 1 > 0,
+            key_release: (bits >> 7) & 1 > 0
+        }
+    }
+}
@@ -1,249 +1,429 @@
 extern crate sdl2;
 
 use sdl2::EventPump;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::GameControllerSubsystem;
+use sdl2::controller::GameController;
 use sdl2::pixels::Color;
-use sdl2::rect::Point;
+use sdl2::rect::{Point, Rect};
 use sdl2::render::Canvas;
-use sdl2::video::Window;
+use sdl2::video::{FullscreenType, Window};
 use sdl2::event::Event;
 use sdl2::keyboard::*;
-use std::collections::{HashMap, HashSet};
-use crate::events::*;
-use crate::save::*;
-
-pub const DISPLAY_WIDTH: u32 = 64;
-pub const DISPLAY_HEIGHT: u32 = 32;
-pub const DISPLAY_REFRESH_RATE: f32 = 60.0; // Hz
-const DISPLAY_SCALE: u32 = 10;
-
-static VALID_KEYS: [Scancode; 16] = [
-    Scancode::Num1, Scancode::Num2, Scancode::Num3, Scancode::Num4,
-    Scancode::Q, Scancode::W, Scancode::E, Scancode::R,
-    Scancode::A, Scancode::S, Scancode::D, Scancode::F,
-    Scancode::Z, Scancode::X, Scancode::C, Scancode::V
-];
-
+use std::collections::HashMap;
+use chip8::audio::*;
+use chip8::config::Scaling;
+use chip8::display::*;
+use chip8::events::*;
+use chip8::gamepad::*;
+use chip8::keymap::*;
+use chip8::keypad::*;
+
+// don't let the audio queue run further ahead of the emulation than this
+const MAX_QUEUED_AUDIO_FRAMES: u32 = 4;
+
+// on-screen messages (save slot confirmations) are drawn with a 3x5 font at this fraction of a lores
+// pixel (3x window pixels at the default scale of 10), for this many frames
+const MESSAGE_SCALE: f32 = 0.3;
+const MESSAGE_FRAMES: u32 = 120;
+
+// the bars around the display when the window doesn't have its aspect ratio
+const LETTERBOX_COLOR: Color = Color::BLACK;
+
+// SDL frontend: owns the window and event pump, presents the core's Display
+// and feeds keyboard and game controller state into its Keypad.
 pub struct Renderer {
-    pixel_buffer: [u64; DISPLAY_HEIGHT as usize],
     display: Option<Canvas<Window>>,
     event_pump: Option<EventPump>,
-    keys_pressed: HashSet<Scancode>,
-    key_to_scancode_table: HashMap<u8, Scancode>,
-    valid_keys_set: HashSet<Scancode>
+    audio_queue: Option<AudioQueue<f32>>,
+    controller_subsystem: Option<GameControllerSubsystem>,
+    controllers: HashMap<u32, GameController>, // open controllers by id, they close when dropped
+    gamepads: Gamepads,
+    key_actions: HashMap<Scancode, KeyAction>,
+    keypad_scancodes: [Vec<Scancode>; NUM_KEYS], // every keyboard key that presses each chip8 key
+    message: Option<(String, u32)>, // text and frames left to show it
+    scaling: Scaling,
+    palette: [Color; 4]             // background, plane 0, plane 1, both planes (only XO-CHIP draws to plane 1)
 }
 
 impl Renderer {
-    pub fn init(&mut self) {
+    // the window starts at 'scale' window pixels per lores pixel and can be resized, palette colours
+    // are 0xRRGGBB, see chip8::config
+    pub fn init(&mut self, scale: u32, scaling: Scaling, fullscreen: bool, palette: [u32; 4], keys: &KeyBindings) -> Result<(), String> {
+        self.scaling = scaling;
+        self.palette = palette.map(|rgb| Color::RGB((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8));
+        self.init_key_actions(keys)?;
+        self.gamepads = make_gamepads(keys);
+
         let sdl_context = sdl2::init().unwrap();
         let event_pump = sdl_context.event_pump().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
 
-        let window = video_subsystem
-            .window("Chip8", DISPLAY_WIDTH * DISPLAY_SCALE, DISPLAY_HEIGHT * DISPLAY_SCALE)
-            .position_centered()
-            .build()
-            .unwrap();
+        let mut window_builder = video_subsystem.window("Chip8", DISPLAY_WIDTH * scale, DISPLAY_HEIGHT * scale);
+        window_builder.position_centered().resizable();
+        if fullscreen {
+            window_builder.fullscreen_desktop();
+        }
+        let window = window_builder.build().unwrap();
 
         let mut canvas = window.into_canvas().build().unwrap();
 
-        canvas.set_scale(DISPLAY_SCALE as f32, DISPLAY_SCALE as f32).unwrap();
-        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        canvas.set_draw_color(self.palette[0]);
         canvas.clear();
         canvas.present();
 
         self.display = Some(canvas);
         self.event_pump = Some(event_pump);
-
-        self.init_key_to_scancode_table();
-        self.init_valid_keys_set();
+        self.audio_queue = open_audio_queue(&sdl_context);
+        // controllers plugged in already show up as added events on the first poll, same as hot-plugged ones
+        self.controller_subsystem = sdl_context.game_controller()
+            .map_err(|err| println!("Failed to init game controllers, keyboard only: {}", err))
+            .ok();
+        return Ok(());
     }
 
-    pub fn save_state(&mut self, save: &mut Save)
+    // key names are SDL scancode names, a key bound to several actions goes to the first (hotkeys come
+    // before the keypad), main reports those conflicts on startup
+    fn init_key_actions(&mut self, keys: &KeyBindings) -> Result<(), String>
     {
-        for row in self.pixel_buffer {
-            let mut r = row;
-            for _ in 0..8 {
-                let byte: u8 = (r & 0xFF) as u8;
-                save.write(byte);
-                r = r >> 8;
+        for (name, action) in keys.bindings() {
+            let scancode = Scancode::from_name(name).ok_or(format!("unknown keyboard key '{}' for {}", name, action))?;
+            self.key_actions.entry(scancode).or_insert(action);
+        }
+
+        for (scancode, action) in self.key_actions.iter() {
+            if let KeyAction::Keypad(chip8_key) = action {
+                self.keypad_scancodes[*chip8_key as usize].push(*scancode);
             }
         }
+
+        return Ok(());
     }
 
-    pub fn load_state(&mut self, save: &mut Save)
+    pub fn step(&mut self, display: &Display)
     {
-        for i in 0..self.pixel_buffer.len() {
-            let mut row: u64 = 0;
-            for j in 0..8 {
-                row = row | ((save.read() as u64) << (j * 8));
+        let canvas = self.display.as_mut().unwrap();
+        canvas.set_scale(1.0, 1.0).unwrap();
+        canvas.set_viewport(None);
+        canvas.set_draw_color(LETTERBOX_COLOR);
+        canvas.clear();
+
+        // hires modes draw smaller pixels into the same area, the window size (or fullscreen) decides how big it is
+        let viewport = display_viewport(canvas.output_size().unwrap(), display.width(), display.height(), self.scaling);
+        canvas.set_viewport(viewport);
+        canvas.set_draw_color(self.palette[0]);
+        canvas.fill_rect(None).unwrap();
+
+        // one batch of rects per colour, colour = (plane 1 bit << 1) | plane 0 bit. Pixel edges are
+        // rounded to window pixels so aspect scaling doesn't leave gaps between them
+        let mut rects: [Vec<Rect>; 4] = Default::default();
+        let edge = |index: u32, count: u32, size: u32| (index as u64 * size as u64 / count as u64) as i32;
+        for (row, (plane0, plane1)) in display.rows(0).iter().zip(display.rows(1)).enumerate()
+        {
+            let row_bits = plane0 | plane1;
+            if row_bits == 0 {
+                continue;
             }
 
-            self.pixel_buffer[i] = row;
+            let top = edge(row as u32, display.height(), viewport.height());
+            let bottom = edge(row as u32 + 1, display.height(), viewport.height());
+            for col in 0..display.width() {
+                if (row_bits >> col) == 0 {
+                    break;
+                }
+
+                let color = ((plane0 >> col) & 1) | (((plane1 >> col) & 1) << 1);
+                if color > 0 {
+                    let left = edge(col, display.width(), viewport.width());
+                    let right = edge(col + 1, display.width(), viewport.width());
+                    rects[color as usize].push(Rect::new(left, top, (right - left) as u32, (bottom - top) as u32));
+                }
+            }
         }
+
+        for (color, color_rects) in rects.iter().enumerate().skip(1) {
+            canvas.set_draw_color(self.palette[color]);
+            canvas.fill_rects(color_rects.as_slice()).unwrap();
+        }
+
+        self.draw_message(viewport);
+        self.refresh_screen();
     }
 
-    fn init_key_to_scancode_table(&mut self)
+    pub fn toggle_fullscreen(&mut self)
     {
-        self.key_to_scancode_table.insert(0x1, Scancode::Num1);
-        self.key_to_scancode_table.insert(0x2, Scancode::Num2);
-        self.key_to_scancode_table.insert(0x3, Scancode::Num3);
-        self.key_to_scancode_table.insert(0xC, Scancode::Num4);
-
-        self.key_to_scancode_table.insert(0x4, Scancode::Q);
-        self.key_to_scancode_table.insert(0x5, Scancode::W);
-        self.key_to_scancode_table.insert(0x6, Scancode::E);
-        self.key_to_scancode_table.insert(0xD, Scancode::R);
-
-        self.key_to_scancode_table.insert(0x7, Scancode::A);
-        self.key_to_scancode_table.insert(0x8, Scancode::S);
-        self.key_to_scancode_table.insert(0x9, Scancode::D);
-        self.key_to_scancode_table.insert(0xE, Scancode::F);
-
-        self.key_to_scancode_table.insert(0xA, Scancode::Z);
-        self.key_to_scancode_table.insert(0x0, Scancode::X);
-        self.key_to_scancode_table.insert(0xB, Scancode::C);
-        self.key_to_scancode_table.insert(0xF, Scancode::V);
+        let window = self.display.as_mut().unwrap().window_mut();
+        let next = if window.fullscreen_state() == FullscreenType::Off { FullscreenType::Desktop } else { FullscreenType::Off };
+        if let Err(err) = window.set_fullscreen(next) {
+            println!("Failed to toggle fullscreen: {}", err);
+        }
     }
 
-    fn init_valid_keys_set(&mut self)
+    // shows 'text' over the top-left corner of the game for a couple of seconds
+    pub fn show_message(&mut self, text: &str)
     {
-        for key in VALID_KEYS {
-            self.valid_keys_set.insert(key);
-        }
+        self.message = Some((text.to_uppercase(), MESSAGE_FRAMES));
     }
 
-    pub fn step(&mut self)
+    fn draw_message(&mut self, viewport: Rect)
     {
-        self.display.as_mut().unwrap().set_draw_color(Color::BLACK);
-        self.display.as_mut().unwrap().clear();
+        let Some((text, frames_left)) = self.message.as_mut() else {
+            return;
+        };
 
         let canvas = self.display.as_mut().unwrap();
-        canvas.set_draw_color(Color::WHITE);
-
-        let mut points: Vec<Point> = Vec::with_capacity((DISPLAY_WIDTH * DISPLAY_HEIGHT / 2) as usize);
-        for (row, color_bitfield) in self.pixel_buffer.iter().enumerate()
-        {
-            if *color_bitfield == 0 {
-                continue;
-            }
-
-            let mut bitfield = *color_bitfield;
-            for col in 0..64 {
-                if (bitfield & 1) > 0 {
-                    points.push(Point::new(col, row as i32));
-                }
-
-                bitfield >>= 1;
-                if bitfield == 0 {
-                    break;
+        let lores_scale = viewport.width() as f32 / DISPLAY_WIDTH as f32;
+        let message_scale = (lores_scale * MESSAGE_SCALE).max(1.0);
+        canvas.set_scale(message_scale, message_scale).unwrap();
+
+        // 3x5 glyphs with a pixel of spacing, on a black box so it reads over any game
+        let width = text.chars().count() as u32 * 4 + 1;
+        canvas.set_draw_color(self.palette[0]);
+        canvas.fill_rect(Rect::new(0, 0, width, 7)).unwrap();
+
+        let mut points: Vec<Point> = Vec::new();
+        for (index, c) in text.chars().enumerate() {
+            for (row, bits) in message_glyph(c).iter().enumerate() {
+                for col in 0..3 {
+                    if (bits >> (2 - col)) & 1 == 1 {
+                        points.push(Point::new(1 + index as i32 * 4 + col, 1 + row as i32));
+                    }
                 }
             }
         }
 
+        canvas.set_draw_color(self.palette[1]);
         canvas.draw_points(points.as_slice()).unwrap();
-        self.refresh_screen();
+
+        *frames_left -= 1;
+        if *frames_left == 0 {
+            self.message = None;
+        }
     }
 
-    // returns true if user requested quit
-    pub fn poll_input(&mut self) -> SystemEvent
+    // returns SystemEvent::Exit if user requested quit. Every queued event is handled, so a key
+    // released in the same frame as a hotkey doesn't stay stuck; the first hotkey's event is returned
+    pub fn poll_input(&mut self, keypad: &mut Keypad) -> SystemEvent
     {
-        for event in self.event_pump.as_mut().unwrap().poll_iter() {
-            match event {
-                Event::Quit {..} => { return SystemEvent::Exit; }
-                Event::KeyDown { scancode: Some(key), .. } => {
-                    match key {
-                        Scancode::Escape => return SystemEvent::Exit,
-                        Scancode::P => return SystemEvent::Pause,
-                        Scancode::Num0 => return SystemEvent::Save,
-                        _ => if self.valid_keys_set.contains(&key) {
-                            self.keys_pressed.insert(key);
-                        }
-                    }
+        // collected first, releasing a chip8 key looks at the keyboard state
+        let events: Vec<Event> = self.event_pump.as_mut().unwrap().poll_iter().collect();
+        let mut sys_event = SystemEvent::None;
+        for event in events {
+            let event_result = match event {
+                Event::Quit {..} => SystemEvent::Exit,
+                Event::KeyDown { scancode: Some(key), keymod, repeat, .. } => match self.key_actions.get(&key) {
+                    // Shift turns the slot keys into load keys, rewinding is handled by is_rewind_held.
+                    // Auto-repeat doesn't trigger hotkeys again
+                    Some(KeyAction::Hotkey(hotkey)) if !repeat => hotkey.event(keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD)),
+                    Some(KeyAction::Keypad(chip8_key)) => {
+                        keypad.set_key(*chip8_key, true);
+                        SystemEvent::None
+                    },
+                    _ => SystemEvent::None
                 },
                 Event::KeyUp { scancode: Some(key), .. } => {
-                    self.keys_pressed.remove(&key);
+                    // stays pressed while another key or button bound to it is still held
+                    if let Some(KeyAction::Keypad(chip8_key)) = self.key_actions.get(&key) {
+                        let held = is_keyboard_holding(self.event_pump.as_ref().unwrap(), &self.keypad_scancodes, *chip8_key);
+                        if !held && !self.gamepads.is_key_held(*chip8_key) {
+                            keypad.set_key(*chip8_key, false);
+                        }
+                    }
+                    SystemEvent::None
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    self.open_controller(which, keypad);
+                    SystemEvent::None
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some(controller) = self.controllers.remove(&which) {
+                        println!("Controller disconnected: {}", controller.name());
+                    }
+                    self.handle_gamepad_event(GamepadEvent::Disconnected(which), keypad)
+                }
+                Event::ControllerButtonDown { which, button, .. } => {
+                    self.handle_gamepad_event(GamepadEvent::ButtonDown(which, button.string()), keypad)
                 }
-                _ => {}
+                Event::ControllerButtonUp { which, button, .. } => {
+                    self.handle_gamepad_event(GamepadEvent::ButtonUp(which, button.string()), keypad)
+                }
+                _ => SystemEvent::None
+            };
+
+            // quitting wins over any other hotkey
+            if sys_event == SystemEvent::None || event_result == SystemEvent::Exit {
+                sys_event = event_result;
             }
         }
 
-        return SystemEvent::None;
+        return sys_event;
     }
 
-    pub fn draw(&mut self, x: u8, y: u8) -> bool {
-        let (row, col) = (y as usize, x as usize);
-        if row >= DISPLAY_HEIGHT as usize || col >= DISPLAY_WIDTH as usize
-        {
-            return false;
-        }
-
-        let curr_pixel = (self.pixel_buffer[row] >> col) & 1;
+    // a released button doesn't let go of a chip8 key the keyboard still holds
+    fn handle_gamepad_event(&mut self, event: GamepadEvent, keypad: &mut Keypad) -> SystemEvent
+    {
+        let (event_pump, keypad_scancodes) = (self.event_pump.as_ref().unwrap(), &self.keypad_scancodes);
+        self.gamepads.handle_event(&event, keypad, |key| is_keyboard_holding(event_pump, keypad_scancodes, key))
+    }
 
-        if (curr_pixel ^ 1) > 0 {
-            self.pixel_buffer[row] |= 1 << col;
-        } else {
-            self.pixel_buffer[row] &= !(1 << col);
+    // 'joystick_index' is SDL's device index, controllers are known by their instance id after that
+    fn open_controller(&mut self, joystick_index: u32, keypad: &mut Keypad)
+    {
+        let Some(subsystem) = self.controller_subsystem.as_ref() else {
+            return;
+        };
+
+        match subsystem.open(joystick_index) {
+            Ok(controller) => {
+                let id = controller.instance_id();
+                println!("Controller connected: {}", controller.name());
+                self.handle_gamepad_event(GamepadEvent::Connected(id), keypad);
+                self.controllers.insert(id, controller);
+            },
+            Err(err) => println!("Failed to open controller {}: {}", joystick_index, err)
         }
-        
-        return curr_pixel > 0;
     }
 
-    pub fn clear_screen(&mut self) {
-        for i in 0..self.pixel_buffer.len() {
-            self.pixel_buffer[i] = 0;
-        }
+    // the rewind hotkey is held down to rewind, checked every frame rather than through key events
+    pub fn is_rewind_held(&mut self) -> bool
+    {
+        let keyboard = self.event_pump.as_ref().unwrap().keyboard_state();
+        self.gamepads.is_rewind_held() || self.key_actions.iter()
+            .any(|(scancode, action)| *action == KeyAction::Hotkey(Hotkey::Rewind) && keyboard.is_scancode_pressed(*scancode))
+    }
+
+    // plays one frame of samples from chip8::audio, dropped if the device is too far behind
+    pub fn queue_audio(&mut self, samples: &[f32])
+    {
+        let Some(queue) = self.audio_queue.as_mut() else {
+            return;
+        };
 
-        self.display.as_mut().unwrap().set_draw_color(Color::BLACK);
-        self.display.as_mut().unwrap().clear();
+        let max_queued_bytes = MAX_QUEUED_AUDIO_FRAMES * (SAMPLES_PER_FRAME * std::mem::size_of::<f32>()) as u32;
+        if queue.size() < max_queued_bytes {
+            queue.queue_audio(samples).unwrap();
+        }
     }
 
     #[inline(always)]
     pub fn refresh_screen(&mut self) {
         self.display.as_mut().unwrap().present();
     }
+}
 
-    pub fn is_key_pressed(&mut self, key: u8) -> bool {
-        let target_key = self.key_to_scancode_table.get(&key).unwrap();
-        for pressed_key in self.keys_pressed.iter() {
-            if *pressed_key == *target_key {
-                return true;
-            }
-        }
-
-        return false;
-    }
-
-    #[inline(always)]
-    pub fn is_any_key_pressed(&mut self) -> bool {
-        self.keys_pressed.len() > 0
+pub fn make_renderer() -> Renderer
+{
+    Renderer {
+        display: None,
+        event_pump: None,
+        audio_queue: None,
+        controller_subsystem: None,
+        controllers: HashMap::new(),
+        gamepads: make_gamepads(&make_key_bindings()), // replaced by init
+        key_actions: HashMap::new(),
+        keypad_scancodes: Default::default(),
+        message: None,
+        scaling: Scaling::Integer, // set by init
+        palette: [Color::BLACK; 4] // set by init
     }
+}
 
-    pub fn get_first_key_pressed(&mut self) -> u8 {
-        if !self.is_any_key_pressed() {
-            return 0;
+// where the display goes in a window of 'window_size' pixels: as large as fits at its aspect ratio
+// (in whole window pixels per chip8 pixel for integer scaling), centred with letterbox bars around it
+fn display_viewport(window_size: (u32, u32), width: u32, height: u32, scaling: Scaling) -> Rect
+{
+    let (window_width, window_height) = window_size;
+    let (viewport_width, viewport_height) = match scaling {
+        Scaling::Integer => {
+            let scale = (window_width / width).min(window_height / height).max(1);
+            (width * scale, height * scale)
+        },
+        Scaling::Aspect => if window_width as u64 * height as u64 <= window_height as u64 * width as u64 {
+            (window_width, window_width * height / width)
+        } else {
+            (window_height * width / height, window_height)
         }
+    };
 
-        for el in self.keys_pressed.iter() {
-            for key in self.key_to_scancode_table.keys() {
-                if *self.key_to_scancode_table.get(key).unwrap() == *el {
-                    return *key;
-                }
-            }
-        }
+    let x = (window_width as i32 - viewport_width as i32) / 2;
+    let y = (window_height as i32 - viewport_height as i32) / 2;
+    return Rect::new(x.max(0), y.max(0), viewport_width.max(1), viewport_height.max(1));
+}
 
-        return 0;
+// 3x5 font for on-screen messages, each row's low 3 bits are its pixels (msb = left)
+fn message_glyph(c: char) -> [u8; 5]
+{
+    match c {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        _ => [0; 5]
     }
+}
 
+// whether any keyboard key bound to the chip8 key is down
+fn is_keyboard_holding(event_pump: &EventPump, keypad_scancodes: &[Vec<Scancode>; NUM_KEYS], chip8_key: u8) -> bool
+{
+    let keyboard = event_pump.keyboard_state();
+    keypad_scancodes[chip8_key as usize].iter().any(|scancode| keyboard.is_scancode_pressed(*scancode))
 }
 
-pub fn make_renderer() -> Renderer
+// no audio device isn't fatal, the emulator just runs silent
+fn open_audio_queue(sdl_context: &sdl2::Sdl) -> Option<AudioQueue<f32>>
 {
-    Renderer {
-        display: None,
-        event_pump: None,
-        pixel_buffer: [0; DISPLAY_HEIGHT as usize],
-        keys_pressed: HashSet::with_capacity(4),
-        key_to_scancode_table: HashMap::new(),
-        valid_keys_set: HashSet::new()
+    let desired_spec = AudioSpecDesired {
+        freq: Some(SAMPLE_RATE as i32),
+        channels: Some(1),
+        samples: None
+    };
+
+    let queue = sdl_context.audio()
+        .and_then(|audio_subsystem| audio_subsystem.open_queue::<f32, _>(None, &desired_spec));
+
+    match queue {
+        Ok(queue) => {
+            queue.resume();
+            Some(queue)
+        },
+        Err(err) => {
+            println!("Failed to open audio device, sound disabled: {}", err);
+            None
+        }
     }
 }
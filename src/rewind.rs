@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+use crate::machine::*;
+use crate::save::*;
+
+pub const DEFAULT_REWIND_MEMORY: usize = 16 * 1024 * 1024;
+pub const DEFAULT_REWIND_INTERVAL: u32 = 2; // frames between snapshots
+
+// Ring buffer of machine snapshots (the save file format) for stepping backwards through play.
+// Only the newest snapshot is kept whole, older ones are stored as the run-length encoded xor
+// against the snapshot after them, which is tiny since little of the ram changes per frame.
+// The oldest deltas are dropped once the buffer goes over its memory budget.
+pub struct Rewind {
+    latest: Vec<u8>,
+    deltas: VecDeque<Vec<u8>>, // deltas[i] turns snapshot i + 1 back into snapshot i, newest at the back
+    max_bytes: usize,
+    used_bytes: usize,
+    interval: u32,
+    frames_since_snapshot: u32
+}
+
+impl Rewind {
+
+    // call once per emulated frame, takes a snapshot every 'interval' frames
+    pub fn record_frame(&mut self, machine: &mut Machine)
+    {
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot < self.interval {
+            return;
+        }
+
+        self.frames_since_snapshot = 0;
+        let mut save = make_save();
+        save.build(machine);
+        let snapshot = save.bytes().to_vec();
+
+        if self.latest.len() == snapshot.len() {
+            let delta = encode_delta(&snapshot, &self.latest);
+            self.used_bytes += delta.len();
+            self.deltas.push_back(delta);
+        } else {
+            // first snapshot (or the state layout changed), start over
+            self.deltas.clear();
+            self.used_bytes = snapshot.len();
+        }
+
+        self.latest = snapshot;
+
+        while self.used_bytes > self.max_bytes {
+            let Some(oldest) = self.deltas.pop_front() else {
+                break;
+            };
+            self.used_bytes -= oldest.len();
+        }
+    }
+
+    // loads the snapshot before the newest one, false once the history runs out
+    pub fn step_back(&mut self, machine: &mut Machine) -> bool
+    {
+        let Some(delta) = self.deltas.pop_back() else {
+            return false;
+        };
+
+        self.used_bytes -= delta.len();
+        apply_delta(&mut self.latest, &delta);
+        self.frames_since_snapshot = 0;
+
+        let mut save = make_save();
+        if let Err(err) = save.load_bytes(self.latest.clone(), machine) {
+            println!("Rewind failed: {}", err);
+            self.clear();
+            return false;
+        }
+
+        return true;
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.latest.clear();
+        self.deltas.clear();
+        self.used_bytes = 0;
+        self.frames_since_snapshot = 0;
+    }
+
+    // number of snapshots that can be stepped back to
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    #[inline(always)]
+    pub fn memory_used(&self) -> usize {
+        self.used_bytes
+    }
+}
+
+// xor of 'newer' and 'older' as (zero run u16, literal length u16, literal bytes) chunks
+fn encode_delta(newer: &[u8], older: &[u8]) -> Vec<u8>
+{
+    let mut delta: Vec<u8> = Vec::new();
+    let xor: Vec<u8> = newer.iter().zip(older).map(|(a, b)| a ^ b).collect();
+
+    let mut i = 0;
+    while i < xor.len() {
+        let zeros_start = i;
+        while i < xor.len() && xor[i] == 0 && i - zeros_start < u16::MAX as usize {
+            i += 1;
+        }
+
+        let literal_start = i;
+        while i < xor.len() && xor[i] != 0 && i - literal_start < u16::MAX as usize {
+            i += 1;
+        }
+
+        delta.extend_from_slice(&((literal_start - zeros_start) as u16).to_le_bytes());
+        delta.extend_from_slice(&((i - literal_start) as u16).to_le_bytes());
+        delta.extend_from_slice(&xor[literal_start..i]);
+    }
+
+    return delta;
+}
+
+fn apply_delta(snapshot: &mut [u8], delta: &[u8])
+{
+    let mut pos = 0;
+    let mut i = 0;
+    while i + 4 <= delta.len() {
+        let zeros = u16::from_le_bytes([delta[i], delta[i + 1]]) as usize;
+        let literal_len = u16::from_le_bytes([delta[i + 2], delta[i + 3]]) as usize;
+        i += 4;
+        pos += zeros;
+
+        for (byte, xor) in snapshot[pos..pos + literal_len].iter_mut().zip(&delta[i..i + literal_len]) {
+            *byte ^= xor;
+        }
+
+        pos += literal_len;
+        i += literal_len;
+    }
+}
+
+// 'max_bytes' bounds the whole buffer, including the newest full snapshot
+pub fn make_rewind(max_bytes: usize, interval: u32) -> Rewind
+{
+    Rewind {
+        latest: Vec::new(),
+        deltas: VecDeque::new(),
+        max_bytes,
+        used_bytes: 0,
+        interval: interval.max(1),
+        frames_since_snapshot: 0
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::config::*;
+use crate::cpu::*;
+use crate::quirks::*;
+
+// Per-rom settings keyed by the sha1 of the rom, in the community CHIP-8 database format
+// (programs.json: a list of programs, each with its roms by sha1). A small database is bundled,
+// <config dir>/chip8/roms.json in the same format adds to it and wins over it.
+const BUNDLED_DATABASE: &str = include_str!("rom_db.json");
+const USER_DATABASE_FILE_NAME: &str = "roms.json";
+
+// COSMAC VIP with the quirks later interpreters dropped turned off, the database's 'modernChip8'
+const MODERN_CHIP8_QUIRKS: Quirks = Quirks { vf_reset: false, display_wait: false, ..VIP_QUIRKS };
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomSettings {
+    pub title: String,
+    pub platform: Option<(CPUMode, Quirks)>, // the first of the rom's platforms this emulator supports
+    pub speed: Option<u32>,                  // instructions per second
+    pub palette: Vec<u32>,                   // 0xRRGGBB, replaces the start of the configured palette
+    pub key_hints: Vec<(String, u8)>         // what the rom uses its keys for, e.g. ("left", 5)
+}
+
+pub struct RomDatabase {
+    roms: HashMap<String, RomSettings> // by lowercase sha1 hex
+}
+
+impl RomDatabase {
+
+    #[inline(always)]
+    pub fn lookup(&self, sha1: &str) -> Option<&RomSettings> {
+        self.roms.get(&sha1.to_lowercase())
+    }
+
+    // entries in 'other' replace the ones already here
+    pub fn extend(&mut self, other: RomDatabase)
+    {
+        self.roms.extend(other.roms);
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.roms.len()
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.roms.is_empty()
+    }
+}
+
+// only the fields used here, the format has plenty more (description, authors, images, ...)
+#[derive(Deserialize)]
+struct ProgramEntry {
+    title: String,
+    #[serde(default)]
+    roms: HashMap<String, RomEntry>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomEntry {
+    #[serde(default)]
+    platforms: Vec<String>,
+    tickrate: Option<u32>, // instructions per frame
+    #[serde(default)]
+    keys: BTreeMap<String, u8>,
+    colors: Option<RomColors>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, BTreeMap<String, bool>>
+}
+
+#[derive(Deserialize)]
+struct RomColors {
+    #[serde(default)]
+    pixels: Vec<String>
+}
+
+pub fn parse_rom_database(json: &str) -> Result<RomDatabase, String>
+{
+    let programs: Vec<ProgramEntry> = serde_json::from_str(json).map_err(|err| err.to_string())?;
+    let mut database = RomDatabase { roms: HashMap::new() };
+    for program in programs {
+        for (sha1, rom) in program.roms {
+            let settings = rom_settings(&program.title, &rom).map_err(|err| format!("{} ({}): {}", program.title, sha1, err))?;
+            database.roms.insert(sha1.to_lowercase(), settings);
+        }
+    }
+
+    return Ok(database);
+}
+
+fn rom_settings(title: &str, rom: &RomEntry) -> Result<RomSettings, String>
+{
+    let platform = rom.platforms.iter().find_map(|id| {
+        let (mode, mut quirks) = platform_mode(id)?;
+        for (quirk, enabled) in rom.quirky_platforms.get(id).into_iter().flatten() {
+            apply_database_quirk(&mut quirks, quirk, *enabled);
+        }
+        Some((mode, quirks))
+    });
+
+    let mut palette = Vec::new();
+    for text in rom.colors.iter().flat_map(|colors| colors.pixels.iter()).take(DEFAULT_PALETTE.len()) {
+        palette.push(parse_color(text).ok_or(format!("invalid colour '{}'", text))?);
+    }
+
+    let key_hints = rom.keys.iter()
+        .filter(|(_, key)| **key < 16)
+        .map(|(action, key)| (action.clone(), *key))
+        .collect();
+
+    Ok(RomSettings {
+        title: title.to_string(),
+        platform,
+        speed: rom.tickrate.filter(|tickrate| *tickrate > 0).map(|tickrate| tickrate * TIMER_CLOCK_SPEED),
+        palette,
+        key_hints
+    })
+}
+
+// platform ids of the database, ones without a matching mode (chip8x, megachip8, ...) are skipped
+fn platform_mode(id: &str) -> Option<(CPUMode, Quirks)>
+{
+    match id {
+        "originalChip8" | "hybridVIP" => Some((CPUMode::Chip8, VIP_QUIRKS)),
+        "modernChip8" => Some((CPUMode::Chip8, MODERN_CHIP8_QUIRKS)),
+        "chip48" => Some((CPUMode::Chip48, CHIP48_QUIRKS)),
+        "superchip1" | "superchip" => Some((CPUMode::SuperChip, SCHIP_QUIRKS)),
+        "xochip" => Some((CPUMode::XoChip, XOCHIP_QUIRKS)),
+        _ => None
+    }
+}
+
+// the database's quirk names mostly describe the opposite of ours
+fn apply_database_quirk(quirks: &mut Quirks, name: &str, enabled: bool)
+{
+    match name {
+        "shift" => quirks.shift_vy = !enabled,
+        "memoryLeaveIUnchanged" => quirks.load_store_inc_i = !enabled,
+        "wrap" => quirks.clip_sprites = !enabled,
+        "jump" => quirks.jump_vx = enabled,
+        "vblank" => quirks.display_wait = enabled,
+        "logic" => quirks.vf_reset = enabled,
+        _ => {} // memoryIncrementByX (I += X on FX55/FX65) isn't emulated
+    }
+}
+
+pub fn bundled_rom_database() -> RomDatabase
+{
+    parse_rom_database(BUNDLED_DATABASE).expect("the bundled rom database is invalid")
+}
+
+pub fn user_rom_database_path() -> Option<PathBuf>
+{
+    config_dir().map(|dir| dir.join(USER_DATABASE_FILE_NAME))
+}
+
+// the bundled database with the user's file (if there is one) on top
+pub fn load_rom_database(user_path: Option<&Path>) -> Result<RomDatabase, String>
+{
+    let mut database = bundled_rom_database();
+    if let Some(path) = user_path.filter(|path| path.exists()) {
+        let json = fs::read_to_string(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        database.extend(parse_rom_database(&json).map_err(|err| format!("{}: {}", path.display(), err))?);
+    }
+
+    return Ok(database);
+}
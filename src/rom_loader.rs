@@ -1,109 +1,114 @@
 #![allow(dead_code, unused_variables)]
 #![allow(unused_assignments)]
+use std::path::Path;
+use crate::assembler;
 use crate::cpu::*;
+use crate::rom_db::*;
+
+const TEST_PROG: &str = "
+    v0 := 1
+    v1 := 5
+    v2 := 8
+    save v2   # dump regs to mem
+    v0 := 0
+    v1 := 0
+    v2 := 0
+    load v2   # load regs from mem
+";
+
+// shows the hex digit of each key pressed
+const ECHO_PROG: &str = "
+: main
+    v0 := key
+    clear
+    i := hex v0
+    sprite v5 v5 5
+    jump main
+";
 
 pub fn load_test_prog(cpu: &mut CPU)
 {
-    unsafe {
-        let mut i = PROG_MEM_START_OFFSET as usize;
-
-        // V0 = 1
-        RAM[i] = 0x60;
-        RAM[i + 1] = 0x01;
-        i += 2;
-
-        // V1 = 5
-        RAM[i] = 0x61;
-        RAM[i + 1] = 0x05;
-        i += 2;
-
-        // V2 = 8
-        RAM[i] = 0x62;
-        RAM[i + 1] = 0x08;
-        i += 2;
-
-        // Dump regs to mem
-        RAM[i] = 0xF2;
-        RAM[i + 1] = 55;
-        i += 2;
-
-        // V0 = 0
-        RAM[i] = 0x60;
-        RAM[i + 1] = 0x00;
-        i += 2;
-
-        // V1 = 0
-        RAM[i] = 0x61;
-        RAM[i + 1] = 0x00;
-        i += 2;
-
-        // V2 = 0
-        RAM[i] = 0x62;
-        RAM[i + 1] = 0x00;
-        i += 2;
-
-        // Load regs from mem
-        RAM[i] = 0xF2;
-        RAM[i + 1] = 65;
-        i += 2;
-    }
-
-    cpu.set_prog_counter(PROG_MEM_START_OFFSET);
+    let rom = assembler::assemble(TEST_PROG).expect("Test program failed to assemble");
+    cpu.load_program(&rom);
     cpu.set_mode(CPUMode::Chip48);
 }
 
 pub fn echo_prog(cpu: &mut CPU)
 {
-    unsafe {
-        let mut i = PROG_MEM_START_OFFSET as usize;
+    let rom = assembler::assemble(ECHO_PROG).expect("Echo program failed to assemble");
+    cpu.load_program(&rom);
+}
 
-        // save key to V0
-        RAM[i] = 0xF0;
-        RAM[i + 1] = 0x0A;
-        i += 2;
+// Loads the rom in the mode (and with the quirks) the rom database lists for it, unless the mode is forced
+// (--mode). Returns the database entry so the frontend can apply the rest (speed, colours, key hints).
+pub fn load_prog(cpu: &mut CPU, prog_path: &Path, database: &RomDatabase, forced_mode: Option<CPUMode>) -> Result<Option<RomSettings>, String>
+{
+    let data = std::fs::read(prog_path).map_err(|err| format!("failed to read rom {}: {}", prog_path.display(), err))?;
+    let settings = database.lookup(&sha1_hex(&data)).cloned();
+
+    // mode goes first, it decides how much memory the rom may use
+    match (forced_mode, settings.as_ref().and_then(|settings| settings.platform)) {
+        (Some(mode), _) => cpu.set_mode(mode),
+        (None, Some((mode, quirks))) => {
+            cpu.set_mode(mode);
+            cpu.set_quirks(quirks);
+        }
+        (None, None) => {}
+    }
 
-        // clear screen
-        RAM[i] = 0x00;
-        RAM[i + 1] = 0xE0;
-        i += 2;
+    if !cpu.load_program(&data) {
+        return Err(format!("{} is {} bytes, too big for {:?} memory", prog_path.display(), data.len(), cpu.mode()));
+    }
 
-        // set font sprite to pressed key (in V0)
-        RAM[i] = 0xF0;
-        RAM[i + 1] = 0x29;
-        i += 2;
+    println!("Loaded {} of size {} bytes", prog_path.display(), data.len());
+    if let Some(settings) = &settings {
+        println!("Found {} in the rom database, running it in {:?} mode", settings.title, cpu.mode());
+    }
+    return Ok(settings);
+}
 
-        // draw
-        RAM[i] = 0xD5;
-        RAM[i + 1] = 0x55;
-        i += 2;
+// sha1 of the rom as lowercase hex, the key of the rom database
+pub fn sha1_hex(data: &[u8]) -> String
+{
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
 
-        // jump to start
-        RAM[i] = 0x12;
-        RAM[i + 1] = 0x00;
-        i += 2;
+    // pad with a 1 bit, zeros and the length in bits to a multiple of 64 bytes
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
     }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
 
-    cpu.set_prog_counter(PROG_MEM_START_OFFSET);
-}
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
 
-pub fn load_prog(cpu: &mut CPU, prog_path: &str) -> bool
-{
-    let data = std::fs::read(prog_path).expect("Failed to read program becuase file couldn't be found");
-    let mut write_ptr = PROG_MEM_START_OFFSET as usize;
-    for byte in data.iter()
-    {
-        if write_ptr >= RAM_SIZE {
-            return false;
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
         }
 
-        unsafe
-        {
-            RAM[write_ptr] = *byte;
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
         }
-        write_ptr += 1;
     }
 
-    cpu.set_prog_counter(PROG_MEM_START_OFFSET);
-    println!("Loaded {} of size {} bytes", prog_path, data.len());
-    return true;
+    return h.iter().map(|word| format!("{:08x}", word)).collect();
 }
@@ -1,11 +1,35 @@
 use std::fs;
+use std::path::{Path, PathBuf};
 use crate::cpu::*;
-use crate::renderer::*;
+use crate::display::*;
+use crate::machine::*;
+use crate::quirks::*;
 
-const SAVE_BUFFER_SIZE: usize = 8192;
+// Save file layout (integers little endian):
+//   magic "C8SV", format version u16, cpu mode u8 (so tools don't need to parse the sections),
+//   has rom hash u8, rom hash u32 (crc32 of the rom)
+//   sections: tag [u8; 4], length u32, data (CPU::save_state / Display::save_state / CPU::save_rng_state)
+//   sections added later are optional on load so older saves keep working
+//   crc32 u32 of everything before it
+// Saves from before the header existed are a headerless 8192 byte blob, they get migrated on load.
+
+const SAVE_BUFFER_SIZE: usize = 0x12000; // 64 KiB XO-CHIP ram + cpu & display state
+const SAVE_MAGIC: [u8; 4] = *b"C8SV";
+const SAVE_FORMAT_VERSION: u16 = 1;
+const SAVE_HEADER_SIZE: usize = 12;
+const SAVE_CRC_SIZE: usize = 4;
+pub const CPU_SECTION: [u8; 4] = *b"CPU ";
+pub const DISPLAY_SECTION: [u8; 4] = *b"DISP";
+pub const RNG_SECTION: [u8; 4] = *b"RNG ";
+pub const KEY_WAIT_SECTION: [u8; 4] = *b"KEYW";
+
+// version 0: 4 KiB ram, pc, I, registers, 32 entry stack, sp, mode (chip8 / chip48), timers, 64x32 pixel rows
+const LEGACY_SAVE_SIZE: usize = 8192;
+const LEGACY_STACK_SIZE: usize = 32;
+const LEGACY_DISPLAY_HEIGHT: usize = 32;
 
 pub struct Save {
-    buffer: [u8; SAVE_BUFFER_SIZE],
+    buffer: Vec<u8>,
     write_ptr: usize,
     read_ptr: usize
 }
@@ -13,60 +37,275 @@ pub struct Save {
 impl Save {
 
     pub fn write(&mut self, byte: u8) {
-        self.buffer[self.write_ptr] = byte;
+        self.buffer.push(byte);
         self.write_ptr += 1;
     }
 
-    pub fn build(&mut self, cpu: &mut CPU, renderer: &mut Renderer) {
-        unsafe {
-            for byte in RAM {
-                self.write(byte);
-            }
+    pub fn write_u16(&mut self, value: u16) {
+        self.write((value & 0xFF) as u8);
+        self.write((value >> 8) as u8);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        for byte in value.to_le_bytes() {
+            self.write(byte);
         }
-        
-        cpu.save_state(self);
-        renderer.save_state(self);
     }
 
-    pub fn write_to_disk(&mut self) {
-        fs::write("save.c8s", self.buffer).unwrap();
+    // writes a tagged section, its length is filled in once 'write_data' is done
+    pub fn write_section(&mut self, tag: [u8; 4], write_data: impl FnOnce(&mut Save)) {
+        for byte in tag {
+            self.write(byte);
+        }
+
+        let length_ptr = self.write_ptr;
+        self.write_u32(0);
+        write_data(self);
+
+        let length = (self.write_ptr - length_ptr - 4) as u32;
+        self.buffer[length_ptr..length_ptr + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub fn build(&mut self, machine: &mut Machine) {
+        self.buffer.clear();
+        self.write_ptr = 0;
+        self.read_ptr = 0;
+
+        for byte in SAVE_MAGIC {
+            self.write(byte);
+        }
+        self.write_u16(SAVE_FORMAT_VERSION);
+        self.write(mode_to_byte(machine.cpu().mode()));
+        let rom_hash = machine.cpu().rom_hash();
+        self.write(rom_hash.is_some() as u8);
+        self.write_u32(rom_hash.unwrap_or(0));
+
+        machine.save_state(self);
+
+        let crc = crc32(&self.buffer);
+        self.write_u32(crc);
+    }
+
+    #[inline(always)]
+    pub fn bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    // creates the slot directory if needed
+    pub fn write_to_disk(&mut self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| format!("failed to create {}: {}", dir.display(), err))?;
+        }
+
+        fs::write(path, &self.buffer).map_err(|err| format!("failed to write {}: {}", path.display(), err))
     }
 
     // Load funcs
 
-    pub fn read(&mut self) -> u8 {
-        if self.read_ptr >= SAVE_BUFFER_SIZE {
-            panic!("Error reading save, tried to read but save buffer is already exhausted");
+    pub fn read(&mut self) -> Result<u8, String> {
+        if self.read_ptr >= self.buffer.len() {
+            return Err("save data ends early".to_string());
         }
 
         let byte = self.buffer[self.read_ptr];
         self.read_ptr += 1;
-        return byte;
+        return Ok(byte);
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        Ok((self.read()? as u16) | ((self.read()? as u16) << 8))
     }
 
-    pub fn read_u16(&mut self) -> u16 {
-        (self.read() as u16) | ((self.read() as u16) << 8)
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes([self.read()?, self.read()?, self.read()?, self.read()?]))
     }
 
-    pub fn load(&mut self, savefile: &str, cpu: &mut CPU, renderer: &mut Renderer) -> bool {
-        let data = std::fs::read(savefile).expect("Failed to read save becuase file couldn't be found");
-        assert!( data.len() <= SAVE_BUFFER_SIZE );
-        for (index, byte) in data.iter().enumerate() {
-            self.buffer[index] = *byte;
+    // hands the data of section 'tag' to 'read_data', which has to consume all of it
+    pub fn read_section(&mut self, tag: [u8; 4], read_data: impl FnOnce(&mut Save) -> Result<(), String>) -> Result<(), String> {
+        let tag_name = String::from_utf8_lossy(&tag).trim().to_string();
+        let Some((start, length)) = self.find_section(tag) else {
+            return Err(format!("save is missing the {} section", tag_name));
+        };
+
+        let mut section = Save { buffer: self.buffer[start..start + length].to_vec(), write_ptr: length, read_ptr: 0 };
+        read_data(&mut section).map_err(|err| format!("{} section: {}", tag_name, err))?;
+        if section.read_ptr != length {
+            return Err(format!("{} section is {} bytes, expected {}", tag_name, length, section.read_ptr));
         }
 
-        unsafe {
-            for i in 0..RAM_SIZE {
-                RAM[i] = self.read();
+        return Ok(());
+    }
+
+    #[inline(always)]
+    pub fn has_section(&self, tag: [u8; 4]) -> bool {
+        self.find_section(tag).is_some()
+    }
+
+    fn find_section(&self, tag: [u8; 4]) -> Option<(usize, usize)> {
+        let sections_end = self.buffer.len().checked_sub(SAVE_CRC_SIZE)?;
+        let mut ptr = SAVE_HEADER_SIZE;
+        while ptr + 8 <= sections_end {
+            let length = u32::from_le_bytes(self.buffer[ptr + 4..ptr + 8].try_into().unwrap()) as usize;
+            if self.buffer[ptr..ptr + 4] == tag {
+                return Some((ptr + 8, length));
             }
+
+            ptr += 8 + length;
         }
 
-        cpu.load_state(self);
-        renderer.load_state(self);
-        return true;
+        return None;
     }
+
+    pub fn load(&mut self, savefile: &Path, machine: &mut Machine) -> Result<(), String> {
+        let data = std::fs::read(savefile).map_err(|err| format!("failed to read {}: {}", savefile.display(), err))?;
+        return self.load_bytes(data, machine);
+    }
+
+    // a save made for another rom is refused while a rom is loaded, a fresh machine takes any save
+    pub fn load_bytes(&mut self, data: Vec<u8>, machine: &mut Machine) -> Result<(), String> {
+        self.buffer = if data.len() == LEGACY_SAVE_SIZE && data[..4] != SAVE_MAGIC { migrate_legacy(&data)? } else { data };
+        self.read_ptr = 0;
+        self.write_ptr = self.buffer.len();
+
+        if self.buffer.len() < SAVE_HEADER_SIZE + SAVE_CRC_SIZE || self.buffer[..4] != SAVE_MAGIC {
+            return Err("not a chip8 save file".to_string());
+        }
+
+        let crc_ptr = self.buffer.len() - SAVE_CRC_SIZE;
+        let stored_crc = u32::from_le_bytes(self.buffer[crc_ptr..].try_into().unwrap());
+        if crc32(&self.buffer[..crc_ptr]) != stored_crc {
+            return Err("save file is corrupt (checksum mismatch)".to_string());
+        }
+
+        self.read_ptr = SAVE_MAGIC.len();
+        let version = self.read_u16()?;
+        if version != SAVE_FORMAT_VERSION {
+            return Err(format!("save format version {} isn't supported, this build reads version {}", version, SAVE_FORMAT_VERSION));
+        }
+
+        let mode_byte = self.read()?;
+        if mode_from_byte(mode_byte).is_none() {
+            return Err(format!("invalid cpu mode {} in save", mode_byte));
+        }
+
+        let has_rom_hash = self.read()? != 0;
+        let rom_hash = self.read_u32()?;
+        let rom_hash = if has_rom_hash { Some(rom_hash) } else { None };
+
+        // walk the section table so a truncated or overlong section is caught before anything is loaded
+        let mut ptr = SAVE_HEADER_SIZE;
+        while ptr < crc_ptr {
+            if ptr + 8 > crc_ptr {
+                return Err("save has a truncated section header".to_string());
+            }
+
+            let length = u32::from_le_bytes(self.buffer[ptr + 4..ptr + 8].try_into().unwrap()) as usize;
+            if length > crc_ptr - ptr - 8 {
+                return Err(format!("{} section runs past the end of the save", String::from_utf8_lossy(&self.buffer[ptr..ptr + 4]).trim()));
+            }
+
+            ptr += 8 + length;
+        }
+
+        if let (Some(loaded), Some(saved)) = (machine.cpu().rom_hash(), rom_hash) {
+            if loaded != saved {
+                return Err(format!("save is for a different rom (rom crc32 {:08x}, save was made with {:08x})", loaded, saved));
+            }
+        }
+
+        machine.load_state(self)?;
+        // a save without a hash (e.g. a migrated legacy one) leaves the running rom's in place
+        if rom_hash.is_some() {
+            machine.cpu_mut().set_rom_hash(rom_hash);
+        }
+        return Ok(());
+    }
+}
+
+// slots live in one directory per rom: <save dir>/<rom name>/slot<n>.c8s
+pub fn slot_path(save_dir: &Path, rom_name: &str, slot: u8) -> PathBuf {
+    save_dir.join(rom_name).join(format!("slot{}.c8s", slot))
+}
+
+// converts a version 0 save into the current layout, chip8 / chip48 only and no rom hash
+fn migrate_legacy(data: &[u8]) -> Result<Vec<u8>, String> {
+    let ram_end = RAM_SIZE;
+    let regs_end = ram_end + 4 + 16 + LEGACY_STACK_SIZE * 2 + 1; // pc, I, registers, stack, sp
+    let mode = mode_from_byte(data[regs_end])
+        .filter(|mode| !mode.has_schip_instrs())
+        .ok_or(format!("invalid cpu mode {} in legacy save", data[regs_end]))?;
+    let timers = &data[regs_end + 1..regs_end + 3];
+    let rows_start = regs_end + 3;
+
+    let mut save = make_save();
+    for byte in SAVE_MAGIC {
+        save.write(byte);
+    }
+    save.write_u16(SAVE_FORMAT_VERSION);
+    save.write(mode_to_byte(mode));
+    save.write(0);
+    save.write_u32(0);
+
+    save.write_section(CPU_SECTION, |section| {
+        for byte in &data[..ram_end] {
+            section.write(*byte);
+        }
+        for _ in ram_end..XO_RAM_SIZE {
+            section.write(0);
+        }
+
+        let stack_start = ram_end + 4 + 16;
+        for byte in &data[ram_end..stack_start + LEGACY_STACK_SIZE * 2] {
+            section.write(*byte);
+        }
+        for _ in 0..MAX_STACK_SIZE.saturating_sub(LEGACY_STACK_SIZE) {
+            section.write_u16(0);
+        }
+
+        section.write(data[regs_end - 1]);
+        section.write(mode_to_byte(mode));
+        section.write(default_quirks(&mode).to_bits());
+        section.write(timers[0]);
+        section.write(timers[1]);
+        for _ in 0..NUM_RPL_FLAGS + AUDIO_PATTERN_SIZE {
+            section.write(0);
+        }
+        section.write(DEFAULT_PITCH);
+    });
+
+    save.write_section(DISPLAY_SECTION, |section| {
+        section.write(0); // lores
+        section.write(1); // plane 0 selected
+        for plane in 0..NUM_PLANES {
+            for row in 0..HIRES_DISPLAY_HEIGHT as usize {
+                // legacy rows were u64, the current ones u128
+                for byte in 0..16 {
+                    let legacy_row = plane == 0 && row < LEGACY_DISPLAY_HEIGHT && byte < 8;
+                    section.write(if legacy_row { data[rows_start + row * 8 + byte] } else { 0 });
+                }
+            }
+        }
+    });
+
+    let crc = crc32(&save.buffer);
+    save.write_u32(crc);
+    return Ok(save.buffer);
+}
+
+// IEEE crc32, for the save checksum and rom hashes
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+
+    return !crc;
 }
 
 pub fn make_save() -> Save {
-    Save { buffer: [0; SAVE_BUFFER_SIZE], write_ptr: 0, read_ptr: 0 }
+    Save { buffer: Vec::with_capacity(SAVE_BUFFER_SIZE), write_ptr: 0, read_ptr: 0 }
 }
@@ -0,0 +1,226 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::assembler;
+use crate::cpu::*;
+use crate::machine::*;
+
+// A rom test is one text file: 'key: value' settings, a '---' line, then the expected final
+// frame as text art (Display::to_text_art). Paths are relative to the test file, '#' starts a comment.
+//
+//   rom: ../../ROMs/IBM Logo.ch8   # .8o sources get assembled first
+//   mode: chip8                    # optional, default chip8
+//   quirks: vip,clip_sprites=off   # optional, same as --quirks
+//   frames: 60
+//   seed: 1                        # optional CXNN seed, default 0
+//   input: 30:5 32:- 40:4,6        # optional, frame:keys held from that frame on (hex keys, '-' for none)
+//   ---
+//   ................
+
+const IMAGE_SEPARATOR: &str = "---";
+const MAX_LISTED_DIFFS: usize = 10;
+
+pub struct RomTest {
+    path: PathBuf,
+    header: String, // everything up to and including the separator, kept as is when updating the image
+    rom: PathBuf,
+    mode: CPUMode,
+    quirks: Option<String>,
+    frames: u32,
+    seed: u64,
+    input: Vec<(u32, u16)>,
+    expected: String
+}
+
+impl RomTest {
+
+    #[inline(always)]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // runs the rom headlessly and returns the final frame as text art
+    pub fn run(&self) -> Result<String, String>
+    {
+        let rom = if self.rom.extension().is_some_and(|ext| ext == "8o") {
+            let source = fs::read_to_string(&self.rom).map_err(|err| format!("failed to read {}: {}", self.rom.display(), err))?;
+            assembler::assemble(&source).map_err(|err| format!("{}:{}", self.rom.display(), err))?
+        } else {
+            fs::read(&self.rom).map_err(|err| format!("failed to read {}: {}", self.rom.display(), err))?
+        };
+
+        let mut machine = make_machine();
+        machine.cpu_mut().set_mode(self.mode);
+        if !machine.cpu_mut().load_program(&rom) {
+            return Err(format!("{} doesn't fit in {:?} memory", self.rom.display(), self.mode));
+        }
+
+        if let Some(spec) = &self.quirks {
+            let mut quirks = machine.cpu().quirks();
+            quirks.apply_overrides(spec)?;
+            machine.cpu_mut().set_quirks(quirks);
+        }
+        machine.cpu_mut().seed_rng(self.seed);
+
+        let mut next_input = self.input.iter().peekable();
+        for frame in 0..self.frames {
+            while let Some((_, keys)) = next_input.next_if(|(start, _)| *start <= frame) {
+                machine.keypad_mut().set_state(*keys);
+            }
+
+            // 00FD just stops the clock and the frame is still compared, any other cpu error fails the test
+            match machine.run_frame() {
+                Ok(()) => {},
+                Err(CpuError::Exit { .. }) => break,
+                Err(err) => return Err(format!("cpu error on frame {}: {}", frame, err))
+            }
+        }
+
+        return Ok(machine.display().to_text_art());
+    }
+
+    // Err holds a description of the mismatch, with a pixel diff
+    pub fn check(&self) -> Result<(), String>
+    {
+        let actual = self.run()?;
+        match diff_text_art(&self.expected, &actual) {
+            Some(diff) => Err(diff),
+            None => Ok(())
+        }
+    }
+
+    // rewrites the image part of the test file with the current output
+    pub fn update_golden(&self) -> Result<(), String>
+    {
+        let actual = self.run()?;
+        fs::write(&self.path, format!("{}{}", self.header, actual)).map_err(|err| format!("failed to write {}: {}", self.path.display(), err))
+    }
+}
+
+pub fn load_rom_test(path: &Path) -> Result<RomTest, String>
+{
+    let text = fs::read_to_string(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut test = RomTest {
+        path: path.to_path_buf(),
+        header: String::new(),
+        rom: PathBuf::new(),
+        mode: CPUMode::Chip8,
+        quirks: None,
+        frames: 0,
+        seed: 0,
+        input: Vec::new(),
+        expected: String::new()
+    };
+
+    let mut lines = text.split_inclusive('\n');
+    let mut found_separator = false;
+    for (line_index, line) in lines.by_ref().enumerate() {
+        test.header.push_str(line);
+        let setting = line.split('#').next().unwrap_or("").trim();
+        if setting == IMAGE_SEPARATOR {
+            found_separator = true;
+            break;
+        }
+
+        if setting.is_empty() {
+            continue;
+        }
+
+        let line_err = |message: String| format!("{}:{}: {}", path.display(), line_index + 1, message);
+        let Some((key, value)) = setting.split_once(':') else {
+            return Err(line_err(format!("expected 'key: value', found '{}'", setting)));
+        };
+
+        let value = value.trim();
+        match key.trim() {
+            "rom" => test.rom = base_dir.join(value),
+            "mode" => test.mode = parse_mode(value).ok_or(line_err(format!("unknown mode '{}'", value)))?,
+            "quirks" => test.quirks = Some(value.to_string()),
+            "frames" => test.frames = value.parse().map_err(|_| line_err(format!("invalid frame count '{}'", value)))?,
+            "seed" => test.seed = value.parse().map_err(|_| line_err(format!("invalid seed '{}'", value)))?,
+            "input" => test.input = parse_input(value).map_err(line_err)?,
+            other => return Err(line_err(format!("unknown setting '{}'", other)))
+        }
+    }
+
+    if !found_separator {
+        return Err(format!("{}: missing the '{}' line before the expected image", path.display(), IMAGE_SEPARATOR));
+    }
+
+    if test.rom.as_os_str().is_empty() {
+        return Err(format!("{}: no rom given", path.display()));
+    }
+
+    test.expected = lines.collect();
+    return Ok(test);
+}
+
+// "30:5 32:- 40:4,6" => [(30, 1 << 5), (32, 0), (40, 1 << 4 | 1 << 6)]
+fn parse_input(value: &str) -> Result<Vec<(u32, u16)>, String>
+{
+    let mut input = Vec::new();
+    for entry in value.split_whitespace() {
+        let Some((frame, keys)) = entry.split_once(':') else {
+            return Err(format!("invalid input '{}', expected frame:keys", entry));
+        };
+
+        let frame: u32 = frame.parse().map_err(|_| format!("invalid input frame '{}'", frame))?;
+        let mut bits: u16 = 0;
+        if keys != "-" {
+            for key in keys.split(',') {
+                match u8::from_str_radix(key, 16) {
+                    Ok(key) if key < 16 => bits |= 1 << key,
+                    _ => return Err(format!("invalid key '{}' in input, expected 0 - F", key))
+                }
+            }
+        }
+
+        input.push((frame, bits));
+    }
+
+    input.sort_by_key(|(frame, _)| *frame);
+    return Ok(input);
+}
+
+// None if the images match, otherwise a summary, the first few differing pixels and the
+// actual image with every differing pixel drawn as 'X'
+pub fn diff_text_art(expected: &str, actual: &str) -> Option<String>
+{
+    let expected_rows: Vec<&str> = expected.lines().collect();
+    let actual_rows: Vec<&str> = actual.lines().collect();
+    let size = |rows: &[&str]| (rows.first().map(|row| row.chars().count()).unwrap_or(0), rows.len());
+    if size(&expected_rows) != size(&actual_rows) {
+        let ((ew, eh), (aw, ah)) = (size(&expected_rows), size(&actual_rows));
+        return Some(format!("expected a {}x{} image, got {}x{}\n{}", ew, eh, aw, ah, actual));
+    }
+
+    let mut mismatches: Vec<(usize, usize, char, char)> = Vec::new();
+    let mut marked = String::with_capacity(actual.len());
+    for (y, (expected_row, actual_row)) in expected_rows.iter().zip(actual_rows.iter()).enumerate() {
+        for (x, (e, a)) in expected_row.chars().zip(actual_row.chars()).enumerate() {
+            if e != a {
+                mismatches.push((x, y, e, a));
+                marked.push('X');
+            } else {
+                marked.push(a);
+            }
+        }
+        marked.push('\n');
+    }
+
+    if mismatches.is_empty() {
+        return None;
+    }
+
+    let mut out = format!("{} pixels differ (x, y: expected -> actual)\n", mismatches.len());
+    for (x, y, e, a) in mismatches.iter().take(MAX_LISTED_DIFFS) {
+        out += &format!("  {}, {}: '{}' -> '{}'\n", x, y, e, a);
+    }
+    if mismatches.len() > MAX_LISTED_DIFFS {
+        out += &format!("  ... and {} more\n", mismatches.len() - MAX_LISTED_DIFFS);
+    }
+
+    out += &marked;
+    return Some(out);
+}
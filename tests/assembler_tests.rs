@@ -0,0 +1,93 @@
+use chip8::assembler::*;
+
+fn words(rom: &[u8]) -> Vec<u16>
+{
+    rom.chunks(2).map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])).collect()
+}
+
+fn assemble_words(source: &str) -> Vec<u16>
+{
+    words(&assemble(source).unwrap_or_else(|err| panic!("{}", err)))
+}
+
+fn error_at(source: &str) -> (usize, usize, String)
+{
+    let err = assemble(source).unwrap_err();
+    (err.line, err.column, err.message)
+}
+
+#[test]
+fn labels_consts_and_aliases()
+{
+    // ': main' after other definitions gets a jump to it at 0x200
+    assert_eq!(assemble_words(":const SPEED 3\n: main\n  v0 := SPEED\n  jump main"), vec![0x1202, 0x6003, 0x1202]);
+    // forward references are patched once the label is known
+    assert_eq!(assemble_words(": start\n  jump end\n: end\n  jump start"), vec![0x1202, 0x1200]);
+    assert_eq!(assemble_words(":alias x v3\nx := 1\n: main x += 1"), vec![0x1204, 0x6301, 0x7301]);
+}
+
+#[test]
+fn if_then_and_if_else_end()
+{
+    // the skip is the opposite of the condition
+    assert_eq!(assemble_words("if v0 == 1 then v1 := 2\nv2 := 3"), vec![0x4001, 0x6102, 0x6203]);
+    assert_eq!(
+        assemble_words("if v0 == 1 begin\n  v1 := 2\nelse\n  v1 := 3\nend\nv2 := 4"),
+        vec![0x3001, 0x1208, 0x6102, 0x120A, 0x6103, 0x6204]
+    );
+}
+
+#[test]
+fn loop_while_again()
+{
+    // while jumps past 'again' when its condition fails
+    assert_eq!(assemble_words("loop\n  v0 += 1\n  while v0 != 5\nagain"), vec![0x7001, 0x4005, 0x1208, 0x1200]);
+}
+
+#[test]
+fn long_and_call()
+{
+    let rom = assemble("i := long data\n:call sub\n: sub return\n: data 0xAB").unwrap();
+    assert_eq!(rom, vec![0xF0, 0x00, 0x02, 0x08, 0x22, 0x06, 0x00, 0xEE, 0xAB]);
+}
+
+#[test]
+fn errors_point_at_line_and_column()
+{
+    assert_eq!(error_at(": main\n  jump nowhere"), (2, 8, "undefined label 'nowhere'".to_string()));
+    assert_eq!(error_at("  v0 := 0x1FF"), (1, 9, "511 is out of range, expected -128 - 255".to_string()));
+    assert_eq!(error_at("v0 += 1\nloop").0, 2);
+    assert_eq!(error_at("v0 := 1\nend"), (2, 1, "'end' without a matching 'if ... begin'".to_string()));
+
+    let err = assemble("v0 := 256").unwrap_err();
+    assert_eq!(err.to_string(), "1:7: 256 is out of range, expected -128 - 255");
+}
+
+#[test]
+fn block_jumps_past_0xfff_are_errors()
+{
+    // 0xE00 bytes of data put the code at 0x1000, out of reach of a 12 bit jump
+    let padding = "0 ".repeat(0xE00);
+    let err = assemble(&format!("{}\nloop\n  v0 += 1\nagain", padding)).unwrap_err();
+    assert_eq!((err.line, err.message.as_str()), (4, "'again' jumps to 0x1000, which doesn't fit in 12 bits"));
+
+    let err = assemble(&format!("{}\nif v0 == 1 begin\n  v1 := 2\nend", padding)).unwrap_err();
+    assert_eq!((err.line, err.message.as_str()), (4, "'end' jumps to 0x1006, which doesn't fit in 12 bits"));
+
+    // the loop starts at 0xFFC, but 'while' breaks out to 0x1002
+    let err = assemble(&format!("{}\nloop\n  while v0 != 5\nagain", "0 ".repeat(0xDFC))).unwrap_err();
+    assert_eq!((err.line, err.message.as_str()), (4, "'again' jumps to 0x1002, which doesn't fit in 12 bits"));
+}
+
+#[test]
+fn program_past_the_end_of_memory_is_an_error()
+{
+    // exactly filling memory is fine
+    let full = "0 ".repeat(0xFE00);
+    assert_eq!(assemble(&full).unwrap().len(), 0xFE00);
+
+    let err = assemble(&format!("{}\n: after", full)).unwrap_err();
+    assert_eq!(err.message, "the program runs past the end of memory at 0x10000");
+    let err = assemble(&format!("{}\n1", full)).unwrap_err();
+    assert_eq!(err.line, 2);
+}
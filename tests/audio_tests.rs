@@ -0,0 +1,118 @@
+mod common;
+
+use chip8::audio::*;
+use chip8::cpu::*;
+use common::*;
+
+const BEEP: &str = "v0 := 30  buzzer := v0  loop again";
+const XO_BEEP: &str = "
+  v0 := 64  pitch := v0
+  i := pattern  audio
+  v0 := 30  buzzer := v0
+  loop again
+: pattern
+  0xFF 0x00 0x00 0x00 0x00 0x00 0x00 0x00 0x00 0x00 0x00 0x00 0x00 0x00 0x00 0x00";
+
+#[test]
+fn silence_without_the_sound_timer()
+{
+    let mut machine = machine_with_source(CPUMode::Chip8, "loop again");
+    machine.run_frame().unwrap();
+    let mut audio = make_audio(DEFAULT_AUDIO_SETTINGS);
+    let mut samples = vec![0.5];
+    audio.generate_frame(machine.cpu(), &mut samples);
+
+    // appends, leaving what's already there alone
+    assert_eq!(samples.len(), 1 + SAMPLES_PER_FRAME);
+    assert_eq!(samples[0], 0.5);
+    assert!(samples[1..].iter().all(|sample| *sample == 0.0));
+}
+
+#[test]
+fn muted_tone_is_silent()
+{
+    let mut machine = machine_with_source(CPUMode::Chip8, BEEP);
+    machine.run_frame().unwrap();
+    let mut audio = make_audio(AudioSettings { muted: true, ..DEFAULT_AUDIO_SETTINGS });
+    let mut samples = Vec::new();
+    audio.generate_frame(machine.cpu(), &mut samples);
+
+    assert!(samples.iter().all(|sample| *sample == 0.0));
+}
+
+#[test]
+fn square_wave_while_the_sound_timer_runs()
+{
+    let mut machine = machine_with_source(CPUMode::Chip8, BEEP);
+    machine.run_frame().unwrap();
+    let volume = DEFAULT_AUDIO_SETTINGS.volume;
+    let mut audio = make_audio(DEFAULT_AUDIO_SETTINGS);
+    let mut samples = Vec::new();
+    audio.generate_frame(machine.cpu(), &mut samples);
+
+    // 440 Hz is a bit over 100 samples per period, high for the first half
+    assert_eq!(samples.len(), SAMPLES_PER_FRAME);
+    assert!(samples.iter().all(|sample| sample.abs() == volume));
+    assert!(samples[..50].iter().all(|sample| *sample == volume));
+    assert!(samples[52..100].iter().all(|sample| *sample == -volume));
+    assert_eq!(samples[102], volume);
+}
+
+#[test]
+fn phase_carries_over_between_frames()
+{
+    let mut machine = machine_with_source(CPUMode::Chip8, BEEP);
+    machine.run_frame().unwrap();
+    let volume = DEFAULT_AUDIO_SETTINGS.volume;
+    let mut audio = make_audio(DEFAULT_AUDIO_SETTINGS);
+    let mut samples = Vec::new();
+    audio.generate_frame(machine.cpu(), &mut samples);
+    samples.clear();
+    audio.generate_frame(machine.cpu(), &mut samples);
+
+    // 735 samples in, a third of the way through a period: high for another 17 samples, not 50
+    assert!(samples[..16].iter().all(|sample| *sample == volume));
+    assert!(samples[18..60].iter().all(|sample| *sample == -volume));
+}
+
+#[test]
+fn xo_chip_plays_the_audio_pattern_at_the_pitch_register_rate()
+{
+    let mut machine = machine_with_source(CPUMode::XoChip, XO_BEEP);
+    machine.run_frame().unwrap();
+    assert_eq!(machine.cpu().pitch(), 64);
+    let volume = DEFAULT_AUDIO_SETTINGS.volume;
+    let mut audio = make_audio(DEFAULT_AUDIO_SETTINGS);
+    let mut samples = Vec::new();
+    audio.generate_frame(machine.cpu(), &mut samples);
+
+    // 4000 bits a second at pitch 64, the 8 set bits last 88 samples, then 120 clear bits
+    assert!(samples[..88].iter().all(|sample| *sample == volume));
+    assert!(samples[89..700].iter().all(|sample| *sample == -volume));
+}
+
+#[test]
+fn wav_header_and_samples()
+{
+    let wav = encode_wav(&[0.0, 1.0, -1.0, 2.0], 22050);
+    let u32_at = |offset: usize| u32::from_le_bytes(wav[offset..offset + 4].try_into().unwrap());
+    let u16_at = |offset: usize| u16::from_le_bytes([wav[offset], wav[offset + 1]]);
+
+    assert_eq!(wav.len(), 44 + 8);
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(u32_at(4), 36 + 8);
+    assert_eq!(&wav[8..16], b"WAVEfmt ");
+    assert_eq!(u32_at(16), 16);
+    assert_eq!(u16_at(20), 1); // PCM
+    assert_eq!(u16_at(22), 1); // mono
+    assert_eq!(u32_at(24), 22050);
+    assert_eq!(u32_at(28), 44100);
+    assert_eq!(u16_at(32), 2);
+    assert_eq!(u16_at(34), 16);
+    assert_eq!(&wav[36..40], b"data");
+    assert_eq!(u32_at(40), 8);
+
+    // out of range samples are clipped
+    let pcm: Vec<i16> = wav[44..].chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+    assert_eq!(pcm, vec![0, i16::MAX, -i16::MAX, i16::MAX]);
+}
@@ -0,0 +1,22 @@
+// Helpers shared by the integration tests, each test file only uses some of them
+#![allow(dead_code)]
+
+use chip8::assembler::*;
+use chip8::cpu::*;
+use chip8::machine::*;
+
+// a fresh machine in 'mode' with 'rom' loaded at 0x200
+pub fn machine_with_rom(mode: CPUMode, rom: &[u8]) -> Machine
+{
+    let mut machine = make_machine();
+    machine.cpu_mut().set_mode(mode);
+    assert!(machine.cpu_mut().load_program(rom));
+    machine
+}
+
+// same with Octo source, see chip8::assembler
+pub fn machine_with_source(mode: CPUMode, source: &str) -> Machine
+{
+    let rom = assemble(source).unwrap_or_else(|err| panic!("{}", err));
+    machine_with_rom(mode, &rom)
+}
@@ -0,0 +1,30 @@
+use chip8::config::*;
+use chip8::quirks::*;
+
+#[test]
+fn default_config_parses_to_the_defaults()
+{
+    assert_eq!(parse_config(&default_config_text()).unwrap(), make_config());
+}
+
+#[test]
+fn quirks_table_applies_over_the_preset()
+{
+    let config = parse_config("[quirks]\nclip_sprites = false\nshift_vy = true\n").unwrap();
+
+    let mut quirks = CHIP48_QUIRKS;
+    config.apply_quirks(&mut quirks);
+    assert_eq!(quirks, Quirks { clip_sprites: false, shift_vy: true, ..CHIP48_QUIRKS });
+
+    // --quirks goes on top
+    quirks.apply_overrides("shift_vy=off").unwrap();
+    assert_eq!(quirks, Quirks { clip_sprites: false, ..CHIP48_QUIRKS });
+}
+
+#[test]
+fn unknown_quirk_is_rejected()
+{
+    let err = parse_config("[quirks]\nwrap = true\n").unwrap_err();
+    assert!(err.contains("unknown quirk 'wrap' in [quirks]"), "{}", err);
+    assert!(parse_config("[quirks]\nshift_vy = \"on\"\n").is_err());
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use chip8::cpu::*;
+use chip8::debugger::*;
+use common::*;
+
+#[test]
+fn exit_ends_the_run_with_a_breakpoint_set()
+{
+    // v0 := 1, exit
+    let mut machine = machine_with_rom(CPUMode::SuperChip, &[0x60, 0x01, 0x00, 0xFD]);
+    let mut debugger = make_debugger();
+    debugger.add_breakpoint(0x300);
+
+    assert!(matches!(debugger.run_frame(&mut machine), Err(CpuError::Exit { .. })));
+    assert!(!debugger.is_paused());
+}
+
+#[test]
+fn invalid_instruction_pauses_only_when_breaking_on_invalid()
+{
+    // 5XY1 isn't an instruction in chip8 mode
+    let mut machine = machine_with_rom(CPUMode::Chip8, &[0x50, 0x01]);
+    let mut debugger = make_debugger();
+    debugger.set_break_on_invalid(false);
+    assert!(debugger.run_frame(&mut machine).is_err());
+    assert!(!debugger.is_paused());
+
+    let mut machine = machine_with_rom(CPUMode::Chip8, &[0x50, 0x01]);
+    let mut debugger = make_debugger();
+    assert!(debugger.run_frame(&mut machine).is_ok());
+    assert!(debugger.is_paused());
+}
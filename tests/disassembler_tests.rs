@@ -0,0 +1,59 @@
+use chip8::cpu::*;
+use chip8::disassembler::*;
+
+#[test]
+fn rom_up_to_the_top_of_memory()
+{
+    // v0 := 0 all the way to 0xFFFF, the last instruction has no next address
+    let rom: Vec<u8> = [0x60, 0x00].repeat(0xFE00 / 2);
+    let lines = disassemble(&rom, CPUMode::XoChip, Syntax::Octo);
+
+    assert_eq!(lines.len(), 0xFE00 / 2);
+    let last = lines.last().unwrap();
+    assert_eq!(last.addr, 0xFFFE);
+    assert!(!last.is_data);
+}
+
+#[test]
+fn rom_past_the_top_of_memory_is_cut_off()
+{
+    let mut rom: Vec<u8> = [0x60, 0x00].repeat(0xFE00 / 2);
+    rom.extend([0x60, 0x00, 0x60]);
+    let lines = disassemble(&rom, CPUMode::XoChip, Syntax::Octo);
+
+    assert_eq!(lines.len(), 0xFE00 / 2);
+    assert_eq!(lines.last().unwrap().addr, 0xFFFE);
+}
+
+#[test]
+fn long_load_in_the_last_word_is_data()
+{
+    let lines = disassemble(&[0x60, 0x00, 0xF0, 0x00], CPUMode::XoChip, Syntax::Octo);
+
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1].addr, 0x202);
+    assert_eq!(lines[1].bytes, vec![0xF0, 0x00]);
+    assert!(lines[1].is_data);
+}
+
+#[test]
+fn long_load_ending_the_rom()
+{
+    let lines = disassemble(&[0x60, 0x00, 0xF0, 0x00, 0x12, 0x34], CPUMode::XoChip, Syntax::Octo);
+
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1].bytes, vec![0xF0, 0x00, 0x12, 0x34]);
+    assert!(!lines[1].is_data);
+    assert_eq!(lines[1].text, "i := long 0x1234");
+}
+
+#[test]
+fn odd_length_rom_ends_with_a_single_byte()
+{
+    let lines = disassemble(&[0x00, 0xE0, 0x12], CPUMode::Chip8, Syntax::Classic);
+
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].text, "CLS");
+    assert_eq!(lines[1].bytes, vec![0x12]);
+    assert!(lines[1].is_data);
+}
@@ -0,0 +1,116 @@
+use chip8::config::*;
+use chip8::events::SystemEvent;
+use chip8::gamepad::*;
+use chip8::keymap::*;
+use chip8::keypad::*;
+
+fn down(id: u32, button: &str) -> GamepadEvent {
+    GamepadEvent::ButtonDown(id, button.to_string())
+}
+
+fn up(id: u32, button: &str) -> GamepadEvent {
+    GamepadEvent::ButtonUp(id, button.to_string())
+}
+
+#[test]
+fn buttons_press_mapped_keys()
+{
+    let mut gamepads = make_gamepads(&make_key_bindings());
+    let mut keypad = make_keypad();
+
+    assert_eq!(gamepads.handle_event(&GamepadEvent::Connected(0), &mut keypad, |_| false), SystemEvent::None);
+    gamepads.handle_event(&down(0, "dpup"), &mut keypad, |_| false);
+    assert!(keypad.is_key_pressed(5));
+    gamepads.handle_event(&up(0, "dpup"), &mut keypad, |_| false);
+    assert!(!keypad.is_key_pressed(5));
+
+    // unbound buttons do nothing
+    gamepads.handle_event(&down(0, "guide"), &mut keypad, |_| false);
+    assert!(!keypad.is_any_key_pressed());
+}
+
+#[test]
+fn key_held_until_every_button_is_released()
+{
+    let config = parse_config("[gamepad]\na = \"5\"\n").unwrap();
+    let mut gamepads = make_gamepads(&config.keys);
+    let mut keypad = make_keypad();
+
+    // dpup on one controller, a on another, both on key 5
+    gamepads.handle_event(&down(0, "dpup"), &mut keypad, |_| false);
+    gamepads.handle_event(&down(1, "a"), &mut keypad, |_| false);
+    gamepads.handle_event(&up(0, "dpup"), &mut keypad, |_| false);
+    assert!(keypad.is_key_pressed(5));
+    gamepads.handle_event(&up(1, "a"), &mut keypad, |_| false);
+    assert!(!keypad.is_key_pressed(5));
+}
+
+#[test]
+fn hotkey_buttons()
+{
+    let config = parse_config("[gamepad]\nx = \"slot3\"\ny = \"load3\"\nguide = \"quit\"\nstart = \"\"\n").unwrap();
+    let mut gamepads = make_gamepads(&config.keys);
+    let mut keypad = make_keypad();
+
+    assert_eq!(gamepads.handle_event(&down(0, "x"), &mut keypad, |_| false), SystemEvent::SaveSlot(3));
+    assert_eq!(gamepads.handle_event(&down(0, "y"), &mut keypad, |_| false), SystemEvent::LoadSlot(3));
+    assert_eq!(gamepads.handle_event(&down(0, "guide"), &mut keypad, |_| false), SystemEvent::Exit);
+    assert_eq!(gamepads.handle_event(&down(0, "start"), &mut keypad, |_| false), SystemEvent::None);
+
+    // held for as long as the button is down
+    assert!(!gamepads.is_rewind_held());
+    gamepads.handle_event(&down(0, "back"), &mut keypad, |_| false);
+    assert!(gamepads.is_rewind_held());
+    gamepads.handle_event(&up(0, "back"), &mut keypad, |_| false);
+    assert!(!gamepads.is_rewind_held());
+}
+
+#[test]
+fn unplugging_releases_held_keys()
+{
+    let mut gamepads = make_gamepads(&make_key_bindings());
+    let mut keypad = make_keypad();
+
+    gamepads.handle_event(&GamepadEvent::Connected(3), &mut keypad, |_| false);
+    gamepads.handle_event(&GamepadEvent::Connected(4), &mut keypad, |_| false);
+    gamepads.handle_event(&down(3, "dpleft"), &mut keypad, |_| false);
+    gamepads.handle_event(&down(4, "a"), &mut keypad, |_| false);
+    gamepads.handle_event(&GamepadEvent::Disconnected(3), &mut keypad, |_| false);
+
+    assert_eq!(gamepads.connected(), &[4]);
+    assert!(!keypad.is_key_pressed(7));
+    assert!(keypad.is_key_pressed(6));
+}
+
+#[test]
+fn per_rom_controller_bindings()
+{
+    let config = parse_config("[gamepad]\na = \"1\"\n[roms.pong.gamepad]\ndpup = \"1\"\ndpdown = \"4\"\n").unwrap();
+    assert_eq!(config.key_bindings("pong").button_action("dpup"), Some(KeyAction::Keypad(1)));
+    assert_eq!(config.key_bindings("pong").button_action("a"), Some(KeyAction::Keypad(1)));
+    assert_eq!(config.key_bindings("tetris").button_action("dpup"), Some(KeyAction::Keypad(5)));
+
+    assert!(parse_config("[gamepad]\nbutton9 = \"1\"\n").is_err());
+    assert!(parse_config("[gamepad]\na = \"jump\"\n").is_err());
+}
+
+#[test]
+fn key_held_on_the_keyboard_outlasts_the_button()
+{
+    let mut gamepads = make_gamepads(&make_key_bindings());
+    let mut keypad = make_keypad();
+
+    // W on the keyboard and dpup both on key 5
+    keypad.set_key(5, true);
+    gamepads.handle_event(&down(0, "dpup"), &mut keypad, |_| false);
+    gamepads.handle_event(&up(0, "dpup"), &mut keypad, |key| key == 5);
+    assert!(keypad.is_key_pressed(5));
+
+    gamepads.handle_event(&down(0, "dpup"), &mut keypad, |_| false);
+    gamepads.handle_event(&GamepadEvent::Disconnected(0), &mut keypad, |key| key == 5);
+    assert!(keypad.is_key_pressed(5));
+
+    gamepads.handle_event(&down(0, "dpup"), &mut keypad, |_| false);
+    gamepads.handle_event(&up(0, "dpup"), &mut keypad, |_| false);
+    assert!(!keypad.is_key_pressed(5));
+}
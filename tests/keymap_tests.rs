@@ -0,0 +1,65 @@
+use chip8::config::*;
+use chip8::keymap::*;
+
+#[test]
+fn default_bindings_have_no_conflicts()
+{
+    assert_eq!(make_key_bindings().conflicts(), Vec::<String>::new());
+}
+
+#[test]
+fn key_bound_to_a_hotkey_and_the_keypad_conflicts()
+{
+    let mut bindings = make_key_bindings();
+    bindings.bind(KeyAction::Keypad(5), vec!["p".to_string(), "W".to_string()]).unwrap();
+
+    // key names aren't case sensitive, the hotkey comes first and wins
+    assert_eq!(bindings.conflicts(), vec!["P is bound to the pause hotkey and chip8 key 5".to_string()]);
+    let actions: Vec<KeyAction> = bindings.bindings().iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("p"))
+        .map(|(_, action)| *action)
+        .collect();
+    assert_eq!(actions, vec![KeyAction::Hotkey(Hotkey::Pause), KeyAction::Keypad(5)]);
+}
+
+#[test]
+fn key_bound_to_two_hotkeys_conflicts()
+{
+    let config = parse_config("[hotkeys]\nquit = \"F1\"\n").unwrap();
+    assert_eq!(config.keys.conflicts(), vec!["F1 is bound to the quit hotkey and the slot1 hotkey".to_string()]);
+
+    // the same key twice for one action is fine
+    let config = parse_config("[keys]\n1 = [\"Q\", \"q\"]\n4 = \"1\"\n").unwrap();
+    assert_eq!(config.keys.conflicts(), Vec::<String>::new());
+}
+
+#[test]
+fn unknown_names_are_rejected()
+{
+    assert_eq!(KeyAction::from_name("a"), Some(KeyAction::Keypad(0xA)));
+    assert_eq!(KeyAction::from_name("load3"), Some(KeyAction::Hotkey(Hotkey::LoadSlot(3))));
+    assert_eq!(KeyAction::from_name("10"), None);
+    assert_eq!(KeyAction::from_name("slot0"), None);
+    assert_eq!(KeyAction::from_name("jump"), None);
+
+    let err = parse_config("[keys]\nG = \"W\"\n").unwrap_err();
+    assert!(err.contains("invalid key 'G' in [keys]"), "{}", err);
+    let err = parse_config("[hotkeys]\njump = \"Space\"\n").unwrap_err();
+    assert!(err.contains("unknown hotkey 'jump' in [hotkeys]"), "{}", err);
+    let err = parse_config("[keys]\n5 = \" \"\n").unwrap_err();
+    assert!(err.contains("empty keyboard key name for chip8 key 5"), "{}", err);
+}
+
+#[test]
+fn per_rom_bindings_win_over_the_global_ones()
+{
+    let config = parse_config("[keys]\n5 = \"Up\"\n[hotkeys]\npause = \"Space\"\n[roms.pong.keys]\n5 = [\"W\", \"I\"]\n").unwrap();
+
+    let pong = config.key_bindings("pong");
+    assert_eq!(pong.keypad[5], vec!["W".to_string(), "I".to_string()]);
+    // everything the rom doesn't set comes from the global tables
+    assert_eq!(pong.hotkey_keys(Hotkey::Pause), &["Space".to_string()]);
+    assert_eq!(pong.keypad[4], vec!["Q".to_string()]);
+
+    assert_eq!(config.key_bindings("tetris").keypad[5], vec!["Up".to_string()]);
+}
@@ -0,0 +1,43 @@
+mod common;
+
+use chip8::cpu::*;
+use chip8::movie::*;
+use common::*;
+
+// v0 := random 0xFF, then loop
+const RANDOM_ROM: [u8; 4] = [0xC0, 0xFF, 0x12, 0x02];
+
+#[test]
+fn replay_uses_the_recorded_rng_script()
+{
+    let mut machine = machine_with_rom(CPUMode::Chip8, &RANDOM_ROM);
+    machine.cpu_mut().set_rng_script(&[0x42]);
+    let mut movie = start_recording(&machine);
+    movie.record_frame(machine.keypad());
+    machine.run_frame().unwrap();
+    assert_eq!(machine.cpu().registers()[0], 0x42);
+
+    // a script left over from the command line doesn't leak into the replay
+    let replay = parse_movie(&movie.to_bytes()).unwrap();
+    let mut machine = machine_with_rom(CPUMode::Chip8, &RANDOM_ROM);
+    machine.cpu_mut().set_rng_script(&[0x17]);
+    replay.start_playback(&mut machine).unwrap();
+    machine.run_frame().unwrap();
+    assert_eq!(machine.cpu().registers()[0], 0x42);
+}
+
+#[test]
+fn truncated_rng_script_is_rejected()
+{
+    let mut machine = machine_with_rom(CPUMode::Chip8, &RANDOM_ROM);
+    machine.cpu_mut().set_rng_script(&[1, 2, 3, 4, 5, 6, 7, 8]);
+    let movie = start_recording(&machine);
+
+    // claim a longer script than the file holds, with a valid checksum
+    let mut data = movie.to_bytes();
+    data.truncate(data.len() - 4);
+    data[25] = 0xFF;
+    let crc = chip8::save::crc32(&data);
+    data.extend_from_slice(&crc.to_le_bytes());
+    assert!(parse_movie(&data).is_err());
+}
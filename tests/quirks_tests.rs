@@ -0,0 +1,22 @@
+use chip8::cpu::*;
+use chip8::quirks::*;
+
+#[test]
+fn only_the_vip_waits_for_the_key_release()
+{
+    assert!(default_quirks(&CPUMode::Chip8).key_release);
+    assert!(!default_quirks(&CPUMode::Chip48).key_release);
+    assert!(!default_quirks(&CPUMode::SuperChip).key_release);
+    assert!(!default_quirks(&CPUMode::XoChip).key_release);
+}
+
+#[test]
+fn overrides_apply_in_order()
+{
+    let mut quirks = VIP_QUIRKS;
+    quirks.apply_overrides("xochip,key_release=on,clip_sprites=1").unwrap();
+    assert_eq!(quirks, Quirks { key_release: true, clip_sprites: true, ..XOCHIP_QUIRKS });
+
+    assert!(quirks.apply_overrides("key_release=maybe").is_err());
+    assert!(quirks.apply_overrides("superchip").is_err());
+}
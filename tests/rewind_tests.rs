@@ -0,0 +1,103 @@
+mod common;
+
+use chip8::cpu::*;
+use chip8::machine::*;
+use chip8::rewind::*;
+use chip8::save::*;
+use common::*;
+
+// counts frames in v0 and scribbles the count over ram, so every snapshot differs
+const COUNTER: &str = "
+: main
+  v1 := 1  vf := 0
+  loop
+    v0 += 1
+    i := 0x400  i += v0  save v0
+    delay := v1
+    loop  vf := delay  if vf != 0 then  again
+  again";
+
+fn state(machine: &mut Machine) -> Vec<u8>
+{
+    let mut save = make_save();
+    save.build(machine);
+    save.bytes().to_vec()
+}
+
+#[test]
+fn step_back_restores_the_earlier_states_exactly()
+{
+    let mut machine = machine_with_source(CPUMode::Chip8, COUNTER);
+    let mut rewind = make_rewind(DEFAULT_REWIND_MEMORY, 1);
+    let mut states = Vec::new();
+    for _ in 0..10 {
+        machine.run_frame().unwrap();
+        rewind.record_frame(&mut machine);
+        states.push(state(&mut machine));
+    }
+    assert_eq!(rewind.len(), 9);
+    assert!(states.windows(2).all(|pair| pair[0] != pair[1]));
+
+    // newest first, the latest snapshot is where the machine already is
+    for expected in states.iter().rev().skip(1) {
+        assert!(rewind.step_back(&mut machine));
+        assert_eq!(&state(&mut machine), expected);
+    }
+    assert!(!rewind.step_back(&mut machine));
+}
+
+#[test]
+fn snapshots_are_taken_every_interval_frames()
+{
+    let mut machine = machine_with_source(CPUMode::Chip8, COUNTER);
+    let mut rewind = make_rewind(DEFAULT_REWIND_MEMORY, 3);
+    for _ in 0..9 {
+        machine.run_frame().unwrap();
+        rewind.record_frame(&mut machine);
+    }
+
+    assert_eq!(rewind.len(), 2);
+}
+
+#[test]
+fn oldest_snapshots_are_dropped_over_the_memory_budget()
+{
+    let mut machine = machine_with_source(CPUMode::Chip8, COUNTER);
+    machine.run_frame().unwrap();
+    let snapshot_size = state(&mut machine).len();
+
+    // room for the latest snapshot and a handful of small deltas
+    let budget = snapshot_size + 200;
+    let mut rewind = make_rewind(budget, 1);
+    for _ in 0..100 {
+        machine.run_frame().unwrap();
+        rewind.record_frame(&mut machine);
+        assert!(rewind.memory_used() <= budget);
+    }
+
+    let kept = rewind.len();
+    assert!(kept > 0 && kept < 99, "{} snapshots kept", kept);
+    for _ in 0..kept {
+        assert!(rewind.step_back(&mut machine));
+    }
+    assert!(!rewind.step_back(&mut machine));
+}
+
+#[test]
+fn rewinding_an_empty_history_leaves_the_machine_alone()
+{
+    let mut machine = machine_with_source(CPUMode::Chip8, COUNTER);
+    machine.run_frame().unwrap();
+    let before = state(&mut machine);
+
+    let mut rewind = make_rewind(DEFAULT_REWIND_MEMORY, 1);
+    assert!(rewind.is_empty());
+    assert!(!rewind.step_back(&mut machine));
+
+    // a single snapshot has nothing before it either
+    rewind.record_frame(&mut machine);
+    assert!(!rewind.step_back(&mut machine));
+    rewind.clear();
+    assert!(!rewind.step_back(&mut machine));
+    assert_eq!(state(&mut machine), before);
+}
@@ -0,0 +1,27 @@
+use std::fs;
+use std::path::Path;
+use chip8::test_runner::*;
+
+// Runs every golden test in tests/golden, refresh the images with 'chip8 test tests/golden --update'
+#[test]
+fn golden_images()
+{
+    let golden_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden");
+    let mut paths: Vec<_> = fs::read_dir(&golden_dir)
+        .expect("failed to read tests/golden")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no golden tests in {}", golden_dir.display());
+
+    let mut failures: Vec<String> = Vec::new();
+    for path in paths.iter() {
+        let result = load_rom_test(path).and_then(|test| test.check());
+        if let Err(err) = result {
+            failures.push(format!("{}:\n{}", path.display(), err));
+        }
+    }
+
+    assert!(failures.is_empty(), "{} of {} golden tests failed\n{}", failures.len(), paths.len(), failures.join("\n"));
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use chip8::cpu::*;
+use chip8::machine::*;
+use chip8::save::*;
+use common::*;
+
+// a few frames in: registers, I, the stack, a timer and the screen all hold something
+const PROGRAM: &str = "
+: main
+  v3 := 0x2A  v0 := 40  delay := v0
+  i := hex v3
+  sprite v0 v0 5
+  draw
+  loop again
+: draw
+  v5 := 7
+  return";
+
+fn running_machine() -> Machine
+{
+    let mut machine = machine_with_source(CPUMode::Chip8, PROGRAM);
+    machine.cpu_mut().set_rom_hash(Some(0x1234_5678));
+    for _ in 0..3 {
+        machine.run_frame().unwrap();
+    }
+    machine
+}
+
+fn save_bytes(machine: &mut Machine) -> Vec<u8>
+{
+    let mut save = make_save();
+    save.build(machine);
+    save.bytes().to_vec()
+}
+
+// replaces the trailing checksum so the damage isn't caught by it
+fn fix_crc(data: &mut Vec<u8>)
+{
+    data.truncate(data.len() - 4);
+    let crc = crc32(data);
+    data.extend_from_slice(&crc.to_le_bytes());
+}
+
+#[test]
+fn round_trip()
+{
+    let mut machine = running_machine();
+    let data = save_bytes(&mut machine);
+
+    let mut loaded = make_machine();
+    make_save().load_bytes(data.clone(), &mut loaded).unwrap();
+    assert_eq!(loaded.cpu().prog_counter(), machine.cpu().prog_counter());
+    assert_eq!(loaded.cpu().index_reg(), machine.cpu().index_reg());
+    assert_eq!(loaded.cpu().registers(), machine.cpu().registers());
+    assert_eq!(loaded.cpu().delay_timer(), machine.cpu().delay_timer());
+    assert_eq!(loaded.cpu().ram(), machine.cpu().ram());
+    assert_eq!(loaded.cpu().rom_hash(), Some(0x1234_5678));
+    assert_eq!(loaded.display().rows(0), machine.display().rows(0));
+
+    // and both carry on the same way
+    machine.run_frame().unwrap();
+    loaded.run_frame().unwrap();
+    assert_eq!(save_bytes(&mut loaded), save_bytes(&mut machine));
+}
+
+#[test]
+fn bad_magic_is_refused()
+{
+    let mut data = save_bytes(&mut running_machine());
+    data[0] = b'X';
+    fix_crc(&mut data);
+
+    let mut machine = make_machine();
+    assert_eq!(make_save().load_bytes(data, &mut machine), Err("not a chip8 save file".to_string()));
+}
+
+#[test]
+fn checksum_mismatch_is_refused_and_leaves_the_machine_alone()
+{
+    let mut data = save_bytes(&mut running_machine());
+    data[100] ^= 0xFF;
+
+    let mut machine = make_machine();
+    let pc = machine.cpu().prog_counter();
+    let err = make_save().load_bytes(data, &mut machine).unwrap_err();
+    assert!(err.contains("checksum mismatch"), "{}", err);
+    assert_eq!(machine.cpu().prog_counter(), pc);
+}
+
+#[test]
+fn truncated_section_is_refused()
+{
+    // the first section (straight after the 12 byte header) claims more than the file holds
+    let mut data = save_bytes(&mut running_machine());
+    let length = u32::from_le_bytes(data[16..20].try_into().unwrap()) + data.len() as u32;
+    data[16..20].copy_from_slice(&length.to_le_bytes());
+    fix_crc(&mut data);
+
+    let mut machine = make_machine();
+    let err = make_save().load_bytes(data, &mut machine).unwrap_err();
+    assert!(err.contains("runs past the end of the save"), "{}", err);
+
+    // a section header cut in half
+    let mut data = save_bytes(&mut running_machine());
+    data.truncate(data.len() - 4);
+    data.extend_from_slice(b"XTRA");
+    data.extend_from_slice(&[0; 4]);
+    fix_crc(&mut data);
+    let err = make_save().load_bytes(data, &mut machine).unwrap_err();
+    assert_eq!(err, "save has a truncated section header");
+}
+
+#[test]
+fn save_for_another_rom_is_refused()
+{
+    let data = save_bytes(&mut running_machine());
+    let mut machine = make_machine();
+    machine.cpu_mut().set_rom_hash(Some(0xCAFE));
+
+    let err = make_save().load_bytes(data, &mut machine).unwrap_err();
+    assert!(err.contains("different rom"), "{}", err);
+}
+
+#[test]
+fn legacy_save_is_migrated()
+{
+    // ram, pc, I, registers, 32 level stack, sp, mode, delay and sound timer, 64x32 screen
+    let ram_end = RAM_SIZE;
+    let mut data = vec![0u8; 8192];
+    data[0x200] = 0x12;
+    data[0x201] = 0x00;
+    data[ram_end..ram_end + 4].copy_from_slice(&[0x02, 0x02, 0x50, 0x00]);
+    data[ram_end + 4 + 3] = 0x2A;
+    let regs_end = ram_end + 4 + 16 + 64 + 1;
+    data[regs_end] = mode_to_byte(CPUMode::Chip48);
+    data[regs_end + 1] = 9;
+    data[regs_end + 3] = 0x01; // top left pixel, bit n is column n
+
+    let mut machine = make_machine();
+    make_save().load_bytes(data, &mut machine).unwrap();
+    assert_eq!(machine.cpu().mode(), CPUMode::Chip48);
+    assert_eq!(machine.cpu().opcode_at(0x200), 0x1200);
+    assert_eq!(machine.cpu().prog_counter(), 0x202);
+    assert_eq!(machine.cpu().index_reg(), 0x50);
+    assert_eq!(machine.cpu().registers()[3], 0x2A);
+    assert_eq!(machine.cpu().delay_timer(), 9);
+    assert_eq!(machine.cpu().rom_hash(), None);
+    assert_eq!(machine.display().rows(0)[0], 0x01);
+    assert!(machine.display().is_pixel_set(0, 0));
+    assert!(machine.display().rows(0)[1..].iter().all(|row| *row == 0));
+}
+
+#[test]
+fn save_without_a_rom_hash_keeps_the_running_roms()
+{
+    let mut data = vec![0u8; 8192];
+    data[RAM_SIZE + 4 + 16 + 64 + 1] = mode_to_byte(CPUMode::Chip8);
+
+    let mut machine = make_machine();
+    machine.cpu_mut().set_rom_hash(Some(0xCAFE));
+    make_save().load_bytes(data, &mut machine).unwrap();
+    assert_eq!(machine.cpu().rom_hash(), Some(0xCAFE));
+}
+
+#[test]
+fn legacy_save_with_a_schip_mode_is_refused()
+{
+    let mut data = vec![0u8; 8192];
+    data[RAM_SIZE + 4 + 16 + 64 + 1] = mode_to_byte(CPUMode::SuperChip);
+
+    let mut machine = make_machine();
+    assert!(make_save().load_bytes(data, &mut machine).is_err());
+}